@@ -42,5 +42,8 @@ async fn main() {
         .expect("Failed to create MongoDB client");
     let messages_to_send = vec![];
     let mut wsclient = WebSocketClient::new(config, None, messages_to_send, mongoclient);
-    wsclient.run().await;
+    if let Err(e) = wsclient.run().await {
+        eprintln!("WebSocket client stopped: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -25,7 +25,7 @@ use std::env;
 use std::sync::Arc;
 use tungstenite::Message;
 use ws2mongo::config::Config;
-use ws2mongo::constants::{*};
+use ws2mongo::constants::*;
 use ws2mongo::mongodb::MongoClient;
 
 #[tokio::main]
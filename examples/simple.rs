@@ -24,7 +24,7 @@
 use ws2mongo::config::Config;
 
 pub fn connect(config: &Config) {
-    println!("Connecting to MongoDB at {}", config.mongodb_uri);
+    println!("Connecting to MongoDB at {}", config.mongodb_uri.redacted());
 }
 
 fn main() {
@@ -25,7 +25,7 @@ use serde_json::json;
 use std::env;
 use tokio_tungstenite::tungstenite::protocol::Message;
 use ws2mongo::config::Config;
-use ws2mongo::utils::pretty_print;
+use ws2mongo::mongodb::MongoClient;
 use ws2mongo::websocket::WebSocketClient;
 
 #[tokio::main]
@@ -51,8 +51,12 @@ async fn main() {
         Message::Text(btc_subscribe.to_string()),
         Message::Text(eth_subscribe.to_string()),
     ];
-    let mut client = WebSocketClient::new(config, None, messages_to_send);
+    let mongoclient = MongoClient::new(config.clone())
+        .await
+        .expect("Failed to create MongoDB client");
+    let mut client = WebSocketClient::new(config, None, messages_to_send, mongoclient);
 
-    // Run the client with the message processing function
-    client.run(pretty_print).await;
+    if let Err(e) = client.run().await {
+        eprintln!("WebSocket client stopped: {}", e);
+    }
 }
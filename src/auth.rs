@@ -0,0 +1,168 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+use crate::config::{AuthMessageConfig, HmacAlgorithm, SignatureEncoding};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde_json::Value;
+use sha2::{Sha256, Sha512};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// The outcome of matching a received message against the authentication response rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthResponse {
+    /// The message confirms the authentication.
+    Accepted,
+
+    /// The message reports that the authentication was rejected.
+    Rejected,
+
+    /// The message says nothing about the authentication (e.g. a welcome banner).
+    Unrelated,
+}
+
+/// Computes the HMAC of `payload` keyed with `secret`.
+///
+/// # Arguments
+///
+/// * `algorithm` - The hash function to use.
+/// * `encoding` - How the resulting bytes are rendered.
+/// * `secret` - The signing key, usually `WEBSOCKET_API_SECRET`.
+/// * `payload` - The string to sign.
+pub fn sign(
+    algorithm: HmacAlgorithm,
+    encoding: SignatureEncoding,
+    secret: &str,
+    payload: &str,
+) -> String {
+    let bytes = match algorithm {
+        HmacAlgorithm::Sha256 => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC accepts keys of any length");
+            mac.update(payload.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+        HmacAlgorithm::Sha512 => {
+            let mut mac = Hmac::<Sha512>::new_from_slice(secret.as_bytes())
+                .expect("HMAC accepts keys of any length");
+            mac.update(payload.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+    };
+
+    match encoding {
+        SignatureEncoding::Hex => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        SignatureEncoding::Base64 => BASE64.encode(bytes),
+    }
+}
+
+/// Returns a random nonce for the `{nonce}` placeholder.
+pub fn generate_nonce() -> String {
+    rand::thread_rng().gen::<u64>().to_string()
+}
+
+/// Replaces the `{api_key}`, `{timestamp}`, `{timestamp_s}` and `{nonce}` placeholders.
+fn expand(template: &str, api_key: &str, timestamp_ms: u64, nonce: &str) -> String {
+    template
+        .replace("{api_key}", api_key)
+        .replace("{timestamp}", &timestamp_ms.to_string())
+        .replace("{timestamp_s}", &(timestamp_ms / 1000).to_string())
+        .replace("{nonce}", nonce)
+}
+
+/// Builds the authentication message from its template.
+///
+/// The signature is computed over the expanded `signature_payload`, using the same timestamp
+/// and nonce as the message itself.
+///
+/// # Arguments
+///
+/// * `auth` - The authentication message configuration.
+/// * `api_key` - The value of the `{api_key}` placeholder.
+/// * `api_secret` - The HMAC signing key.
+/// * `timestamp_ms` - Milliseconds since the epoch.
+/// * `nonce` - The value of the `{nonce}` placeholder.
+pub fn build_auth_message(
+    auth: &AuthMessageConfig,
+    api_key: &str,
+    api_secret: &str,
+    timestamp_ms: u64,
+    nonce: &str,
+) -> String {
+    let message = expand(&auth.template, api_key, timestamp_ms, nonce);
+    if !message.contains("{signature}") {
+        return message;
+    }
+
+    let payload = expand(&auth.signature_payload, api_key, timestamp_ms, nonce);
+    let signature = sign(
+        auth.signature_algorithm,
+        auth.signature_encoding,
+        api_secret,
+        &payload,
+    );
+    message.replace("{signature}", &signature)
+}
+
+/// Checks whether the value at `pointer` matches `expected`. An empty `expected` matches any value.
+fn pointer_matches(json: &Value, pointer: &str, expected: &str) -> bool {
+    match json.pointer(pointer) {
+        Some(Value::String(value)) => expected.is_empty() || value == expected,
+        Some(value) => {
+            expected.is_empty()
+                || serde_json::from_str::<Value>(expected).is_ok_and(|expected| &expected == value)
+        }
+        None => false,
+    }
+}
+
+/// Classifies a message received while waiting for the authentication response.
+///
+/// # Arguments
+///
+/// * `auth` - The authentication message configuration holding the response rules.
+/// * `message` - The received message. Only JSON text or binary frames can match.
+pub fn classify_auth_response(auth: &AuthMessageConfig, message: &Message) -> AuthResponse {
+    let json = match message {
+        Message::Text(text) => serde_json::from_str::<Value>(text).ok(),
+        Message::Binary(data) => serde_json::from_slice::<Value>(data).ok(),
+        _ => None,
+    };
+    let Some(json) = json else {
+        return AuthResponse::Unrelated;
+    };
+
+    if let Some(pointer) = &auth.failure_pointer {
+        if pointer_matches(&json, pointer, &auth.failure_value) {
+            return AuthResponse::Rejected;
+        }
+    }
+    if let Some(pointer) = &auth.success_pointer {
+        if pointer_matches(&json, pointer, &auth.success_value) {
+            return AuthResponse::Accepted;
+        }
+    }
+    AuthResponse::Unrelated
+}
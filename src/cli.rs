@@ -0,0 +1,116 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+use crate::config::{Config, ConfigError};
+use crate::constants::DEFAULT_PIPELINE_NAME;
+use crate::utils::LogLevel;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Streams JSON messages from WebSocket sources into MongoDB.
+#[derive(Parser, Debug)]
+#[command(name = "ws2mongo", version, about)]
+pub struct Cli {
+    /// TOML or YAML config file. Without it, settings come from environment variables only.
+    #[arg(short, long, global = true, env = "WS2MONGO_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// JSON array or NDJSON file of messages sent after every (re)connection. It replaces the
+    /// initial messages of every pipeline.
+    #[arg(short, long, global = true)]
+    pub messages: Option<PathBuf>,
+
+    /// Verbosity: error, warn, info or debug (prints every received message).
+    #[arg(short, long, global = true, env = "LOG_LEVEL", default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+
+    /// What to do. Defaults to `run`.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// The subcommands of the `ws2mongo` binary.
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Start ingesting every pipeline.
+    Run,
+
+    /// Load the configuration and print it, without connecting anywhere.
+    CheckConfig,
+
+    /// Check the MongoDB connection of every pipeline.
+    PingMongo,
+
+    /// Connect to a WebSocket source, send the initial messages and print the frames received,
+    /// without writing to MongoDB.
+    ProbeWs {
+        /// The pipeline to probe. Defaults to the first one.
+        #[arg(short, long)]
+        pipeline: Option<String>,
+
+        /// Number of frames to print before disconnecting.
+        #[arg(short = 'n', long, default_value_t = 10)]
+        frames: usize,
+    },
+
+    /// Print the version.
+    Version,
+}
+
+impl Cli {
+    /// Returns the subcommand to run, `Command::Run` if none was given.
+    pub fn command(&self) -> Command {
+        self.command.clone().unwrap_or(Command::Run)
+    }
+
+    /// Loads the pipelines described by the config file or the environment.
+    ///
+    /// # Returns
+    ///
+    /// The pipeline names and configurations. Without a config file, or with a file that
+    /// declares no pipelines, there is a single pipeline named `default`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ConfigError` if the configuration or the messages file is invalid, including
+    /// the problems found by `Config::validate`.
+    pub fn load_pipelines(&self) -> Result<Vec<(String, Config)>, ConfigError> {
+        let mut pipelines = match &self.config {
+            Some(path) => Config::pipelines_from_file(path)?,
+            None => vec![(DEFAULT_PIPELINE_NAME.to_string(), Config::new()?)],
+        };
+        if let Some(path) = &self.messages {
+            let messages = Config::load_messages_file(path)?;
+            for (_, config) in &mut pipelines {
+                config.websocket_initial_messages = messages.clone();
+            }
+        }
+        for (name, config) in &pipelines {
+            config.validate().map_err(|e| match name.as_str() {
+                DEFAULT_PIPELINE_NAME => e,
+                _ => ConfigError::Pipeline(name.clone(), Box::new(e)),
+            })?;
+        }
+        Ok(pipelines)
+    }
+}
@@ -16,15 +16,27 @@
  ******************************************************************************/
 
 /******************************************************************************
-    Author:  
-    Email: jb@taunais.com 
-    Date: 17/5/24
- ******************************************************************************/
+   Author:
+   Email: jb@taunais.com
+   Date: 17/5/24
+******************************************************************************/
 
 pub const WEBSOCKET_URL: &str = "ws://localhost:5678";
+pub const WEBSOCKET_AUTH_SIGNATURE_PAYLOAD: &str = "{timestamp}{nonce}";
+pub const WEBSOCKET_AUTH_TIMEOUT_MS: u64 = 10_000;
+pub const WEBSOCKET_RECONNECT_INITIAL_DELAY_MS: u64 = 500;
+pub const WEBSOCKET_RECONNECT_MAX_DELAY_MS: u64 = 30_000;
+pub const WEBSOCKET_RECONNECT_MULTIPLIER: f64 = 2.0;
+pub const WEBSOCKET_RECONNECT_RESET_AFTER_MS: u64 = 60_000;
 pub const MONGODB_URI: &str = "mongodb://localhost:27017";
 pub const MONGODB_AUTH_SOURCE: &str = "admin";
 pub const MONGODB_AUTH_MECHANISM: &str = "SCRAM-SHA-256";
+pub const MONGODB_BATCH_SIZE: usize = 500;
+pub const MONGODB_BATCH_TIMEOUT_MS: u64 = 1000;
+pub const ENVELOPE_KEY: &str = "payload";
+pub const DEFAULT_PIPELINE_NAME: &str = "default";
+pub const PIPELINE_STATUS_INTERVAL_MS: u64 = 60_000;
+pub const REDACTED: &str = "***";
 
 pub const MECHANISM_SCRAM_SHA_256: &str = "SCRAM-SHA-256";
 
@@ -34,8 +46,18 @@ pub const MECHANISM_MONGODB_CR: &str = "MONGODB-CR";
 
 pub const MECHANISM_PLAIN: &str = "PLAIN";
 
-pub const MECHANISM_MONGODB_AWS: &str = "MONGODB_AWS";
+pub const MECHANISM_MONGODB_AWS: &str = "MONGODB-AWS";
 
+// Not implemented by the MongoDB driver, so not part of MECHANISMS
 pub const MECHANISM_GSSAPI: &str = "GSSAPI";
 
 pub const MECHANISM_MONGODB_X509: &str = "MONGODB-X509";
+
+pub const MECHANISMS: [&str; 6] = [
+    MECHANISM_SCRAM_SHA_256,
+    MECHANISM_SCRAM_SHA_1,
+    MECHANISM_MONGODB_CR,
+    MECHANISM_PLAIN,
+    MECHANISM_MONGODB_AWS,
+    MECHANISM_MONGODB_X509,
+];
@@ -0,0 +1,59 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+use crate::config::ConfigError;
+use crate::mongodb::MongoClientError;
+use crate::pipeline::PipelineError;
+use crate::utils::DecodeError;
+use crate::websocket::WebSocketError;
+use thiserror::Error;
+
+/// The crate-wide error type, grouping the errors of every module.
+///
+/// Each module returns its own error enum; this type lets callers that drive several of them
+/// (e.g. a supervisor running the whole bridge) use `?` and still match on the source.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// Error raised while loading the configuration.
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    /// Error raised by the WebSocket client (connect, handshake, authentication, transport).
+    #[error(transparent)]
+    WebSocket(#[from] WebSocketError),
+
+    /// Error raised by the MongoDB client (connection, enqueueing, writes).
+    #[error(transparent)]
+    Mongo(#[from] MongoClientError),
+
+    /// Error raised while decoding a WebSocket message.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    /// Error raised while starting a pipeline.
+    #[error(transparent)]
+    Pipeline(#[from] PipelineError),
+}
+
+/// A `Result` alias using the crate-wide `Error`.
+pub type Result<T> = std::result::Result<T, Error>;
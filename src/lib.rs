@@ -25,7 +25,17 @@ pub mod config;
 
 pub mod websocket;
 
+pub mod subscription;
+
+pub mod auth;
+
 pub mod mongodb;
 pub mod utils;
 
+pub mod pipeline;
+
+pub mod cli;
+
 pub mod constants;
+
+pub mod error;
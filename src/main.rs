@@ -21,43 +21,180 @@
    Date: 11/5/24
 ******************************************************************************/
 
-use serde_json::json;
-use std::env;
+use clap::Parser;
+use std::process::ExitCode;
+use std::time::Duration;
 use tokio_tungstenite::tungstenite::protocol::Message;
-use ws2mongo::config::Config;
-use ws2mongo::mongodb::MongoClient;
-use ws2mongo::websocket::WebSocketClient;
+use ws2mongo::cli::{Cli, Command};
+use ws2mongo::config::{pipeline_status_interval_ms, Config};
+use ws2mongo::error::Result;
+use ws2mongo::mongodb::{MongoClient, MongoClientError};
+use ws2mongo::pipeline::{Pipeline, PipelineError, PipelineState, PipelineSupervisor};
+use ws2mongo::utils::{log_enabled, set_log_level, LogLevel};
+use ws2mongo::websocket::{initial_messages, WebSocketClient};
 
+/// Runs the selected command; every failure is reported here and mapped to a non-zero exit code,
+/// so the commands return normally and their destructors (e.g. the writer flush) always run.
 #[tokio::main]
-async fn main() {
-    env::set_var("DATABASE_NAME", "test");
-    env::set_var("COLLECTION_NAME", "test");
-    let config = Config::new().expect("Failed to load config");
-    match config.print_as_json() {
-        Ok(json) => println!("{}", json),
-        Err(e) => eprintln!("Error serializing config: {}", e),
-    }
-    // Create messages as JSON objects
-    let btc_subscribe = json!({
-        "type": "subscribe",
-        "symbol": "BTCUSD"
-    });
-
-    let eth_subscribe = json!({
-        "type": "subscribe",
-        "symbol": "ETHUSD"
-    });
-
-    // Convert JSON objects to string and wrap them as WebSocket messages
-    let messages_to_send = vec![
-        Message::Text(btc_subscribe.to_string()),
-        Message::Text(eth_subscribe.to_string()),
-    ];
-
-    let mongoclient = MongoClient::new(config.clone())
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    set_log_level(cli.log_level);
+
+    let command = cli.command();
+    if command == Command::Version {
+        println!("ws2mongo {}", env!("CARGO_PKG_VERSION"));
+        return ExitCode::SUCCESS;
+    }
+
+    let pipelines = match cli.load_pipelines() {
+        Ok(pipelines) => pipelines,
+        Err(e) => {
+            eprintln!("Failed to load config: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match command {
+        // `Version` was handled before loading the configuration
+        Command::Run | Command::Version => run(pipelines).await,
+        Command::CheckConfig => {
+            check_config(&pipelines);
+            Ok(())
+        }
+        Command::PingMongo => ping_mongo(&pipelines).await,
+        Command::ProbeWs { pipeline, frames } => {
+            probe_ws(&pipelines, pipeline.as_deref(), frames).await
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs every pipeline until all of them stopped, reporting their status periodically.
+///
+/// # Errors
+///
+/// Returns an error if the pipelines disagree on their status interval or one of them cannot be
+/// started, or `PipelineError::Failed` naming the pipelines that ended in the failed state.
+async fn run(pipelines: Vec<(String, Config)>) -> Result<()> {
+    if log_enabled(LogLevel::Info) {
+        print_configs(&pipelines);
+    }
+
+    let status_interval_ms = pipeline_status_interval_ms(&pipelines)?;
+    let pipelines = pipelines
+        .into_iter()
+        .map(|(name, config)| Pipeline::new(name, config))
+        .collect();
+    let supervisor = PipelineSupervisor::start(pipelines).await?;
+
+    if status_interval_ms > 0 {
+        let mut interval = tokio::time::interval(Duration::from_millis(status_interval_ms));
+        interval.tick().await; // The first tick completes immediately
+        loop {
+            tokio::select! {
+                _ = supervisor.wait() => break,
+                _ = interval.tick() => {
+                    if log_enabled(LogLevel::Info) {
+                        for status in supervisor.status() {
+                            println!("Pipeline {}", status);
+                        }
+                    }
+                }
+            }
+        }
+    } else {
+        supervisor.wait().await;
+    }
+
+    let statuses = supervisor.status();
+    for status in &statuses {
+        println!("Pipeline {}", status);
+    }
+    let failed: Vec<String> = statuses
+        .into_iter()
+        .filter(|status| matches!(status.state, PipelineState::Failed(_)))
+        .map(|status| status.name)
+        .collect();
+    if !failed.is_empty() {
+        return Err(PipelineError::Failed(failed).into());
+    }
+    Ok(())
+}
+
+/// Prints the configuration of every pipeline.
+fn check_config(pipelines: &[(String, Config)]) {
+    print_configs(pipelines);
+    println!("Configuration OK: {} pipeline(s)", pipelines.len());
+}
+
+/// Prints the configuration of every pipeline as JSON.
+fn print_configs(pipelines: &[(String, Config)]) {
+    for (name, config) in pipelines {
+        match config.print_as_json() {
+            Ok(json) => println!("Pipeline {}: {}", name, json),
+            Err(e) => eprintln!("Error serializing config: {}", e),
+        }
+    }
+}
+
+/// Checks the MongoDB connection of every pipeline.
+///
+/// # Errors
+///
+/// Returns `PipelineError::Failed` naming the pipelines whose MongoDB server is unreachable.
+async fn ping_mongo(pipelines: &[(String, Config)]) -> Result<()> {
+    let mut failed = Vec::new();
+    for (name, config) in pipelines {
+        match MongoClient::connect(config).await {
+            Ok(_) => println!("Pipeline {}: MongoDB is reachable", name),
+            Err(e) => {
+                eprintln!("Pipeline {}: {}", name, e);
+                failed.push(name.clone());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(PipelineError::Failed(failed).into());
+    }
+    Ok(())
+}
+
+/// Connects to the WebSocket source of a pipeline and prints the first `frames` frames.
+///
+/// # Errors
+///
+/// Returns `PipelineError::Unknown` if no pipeline has the requested name, or the error raised
+/// while connecting or receiving.
+async fn probe_ws(pipelines: &[(String, Config)], name: Option<&str>, frames: usize) -> Result<()> {
+    let found = match name {
+        Some(name) => pipelines.iter().find(|(pipeline, _)| pipeline == name),
+        None => pipelines.first(),
+    };
+    let Some((name, config)) = found else {
+        return Err(PipelineError::Unknown(name.unwrap_or_default().to_string()).into());
+    };
+
+    // Nothing is enqueued, so the MongoDB client is never asked to connect
+    let client = mongodb::Client::with_uri_str(config.mongodb_uri.expose())
         .await
-        .expect("Failed to create MongoDB client");
+        .map_err(MongoClientError::Options)?;
+    let mongo_client = MongoClient::with_client(&client, config);
+    let mut ws_client =
+        WebSocketClient::new(config.clone(), None, initial_messages(config), mongo_client);
 
-    let mut wsclient = WebSocketClient::new(config, None, messages_to_send, mongoclient);
-    wsclient.run().await;
+    ws_client.connect().await?;
+    println!("Pipeline {}: connected to {}", name, config.websocket_url);
+    for _ in 0..frames {
+        match ws_client.receive_message().await? {
+            Message::Text(text) => println!("{}", text),
+            message => println!("{:?}", message),
+        }
+    }
+    Ok(())
 }
@@ -21,21 +21,874 @@
    Date: 11/5/24
 ******************************************************************************/
 
-use crate::config::Config;
-use crate::constants::{*};
-use mongodb::bson::Document;
-use mongodb::options::{AuthMechanism, ClientOptions};
+use crate::config::{
+    check_collection_name, default_index_name, index_direction, Coercion, CollectionRoute, Config,
+    EnvelopeMode, ErrorPolicy, Granularity, IndexSpec, TimeUnit, WriteMode,
+};
+use crate::constants::*;
+use crate::utils::{log_enabled, DecodeError, LogLevel};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{Bson, DateTime, Decimal128, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{
+    AuthMechanism, ClientOptions, CreateCollectionOptions, Credential, IndexOptions,
+    InsertManyOptions, ReplaceOptions, TimeseriesGranularity, TimeseriesOptions, Tls, TlsOptions,
+    UpdateOptions,
+};
+use mongodb::results::CollectionType;
 use serde_json::Value;
-use std::error::Error;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::Mutex;
+use tokio::time::Instant;
 use tokio_tungstenite::tungstenite::protocol::Message;
 
 use mongodb::{
     bson::doc, error::Error as MongoError, error::Result as MongoResult, Client, Collection,
+    Database, IndexModel,
 };
 
+/// An enum representing the errors that can occur in `MongoClient`.
+#[derive(Error, Debug)]
+pub enum MongoClientError {
+    /// Error indicating that the MongoDB URI or client options are invalid.
+    #[error("invalid MongoDB options: {0}")]
+    Options(#[source] MongoError),
+
+    /// Error indicating that the configured authentication mechanism is not supported.
+    #[error("unsupported auth mechanism: {0}")]
+    UnsupportedAuthMechanism(String),
+
+    /// Error indicating that the connection check against MongoDB failed.
+    #[error("failed to connect to MongoDB: {0}")]
+    Connect(#[source] MongoError),
+
+    /// Error indicating that MongoDB rejected a write.
+    #[error("failed to write to MongoDB: {0}")]
+    Write(#[source] MongoError),
+
+    /// Error indicating that MongoDB rejected a single document of a batch.
+    #[error("MongoDB rejected the document: {0}")]
+    Insert(String),
+
+    /// Error indicating that the writer task no longer accepts documents. The rejected message
+    /// is boxed to keep the error small.
+    #[error("failed to enqueue message: {0}")]
+    Enqueue(#[from] Box<SendError<Received>>),
+
+    /// Error indicating that the message could not be decoded into a document.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    /// Error indicating that a JSON value could not be converted to a BSON document.
+    #[error("failed to convert JSON to a BSON document: {0}")]
+    Convert(#[source] mongodb::bson::ser::Error),
+
+    /// Error indicating that a JSON payload is a scalar instead of an object or an array.
+    #[error("JSON payload is neither an object nor an array: {0}")]
+    NotADocument(String),
+
+    /// Error indicating that a payload field cannot be converted to the type of its coercion.
+    #[error("failed to coerce field: {0}")]
+    Coerce(String),
+
+    /// Error indicating that the time of a time-series document is missing or unreadable.
+    #[error("invalid time-series time: {0}")]
+    TimeField(String),
+
+    /// Error indicating that a document lacks one of the key fields of keyed writes.
+    #[error("document has no key field {0}")]
+    MissingKey(String),
+
+    /// Error indicating that an index could not be created, with the collection and index names.
+    #[error("failed to create index {1} on {0}: {2}")]
+    CreateIndex(String, String, #[source] MongoError),
+
+    /// Error indicating that the indexes of a collection could not be listed.
+    #[error("failed to list indexes of {0}: {1}")]
+    ListIndexes(String, #[source] MongoError),
+
+    /// Error indicating that an existing index differs from a declared one with the same name
+    /// or keys.
+    #[error("index conflict on {0}: {1}")]
+    IndexConflict(String, String),
+
+    /// Error indicating that a collection could not be created.
+    #[error("failed to create collection {0}: {1}")]
+    CreateCollection(String, #[source] MongoError),
+
+    /// Error indicating that a routing rule picked a collection name MongoDB does not accept.
+    #[error("invalid routed collection name {0:?}: {1}")]
+    InvalidCollection(String, String),
+
+    /// Error indicating that a document was not written because its routed collection could not
+    /// be prepared.
+    #[error("collection is not ready: {0}")]
+    CollectionNotReady(String),
+}
+
+impl MongoClientError {
+    /// Returns a short, stable name for the kind of error, as stored in dead-letter entries.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MongoClientError::Options(_) => "options",
+            MongoClientError::UnsupportedAuthMechanism(_) => "unsupported_auth_mechanism",
+            MongoClientError::Connect(_) => "connect",
+            MongoClientError::Write(_) => "write",
+            MongoClientError::Insert(_) => "insert",
+            MongoClientError::Enqueue(_) => "enqueue",
+            MongoClientError::Decode(DecodeError::Json(_)) => "decode",
+            MongoClientError::Decode(DecodeError::UnsupportedFrame(_)) => "unsupported_frame",
+            MongoClientError::Convert(_) => "convert",
+            MongoClientError::NotADocument(_) => "not_a_document",
+            MongoClientError::Coerce(_) => "coerce",
+            MongoClientError::TimeField(_) => "time_field",
+            MongoClientError::MissingKey(_) => "missing_key",
+            MongoClientError::CreateIndex(_, _, _) => "create_index",
+            MongoClientError::ListIndexes(_, _) => "list_indexes",
+            MongoClientError::IndexConflict(_, _) => "index_conflict",
+            MongoClientError::CreateCollection(_, _) => "create_collection",
+            MongoClientError::InvalidCollection(_, _) => "invalid_collection",
+            MongoClientError::CollectionNotReady(_) => "collection_not_ready",
+        }
+    }
+}
+
+/// Identifies the connection a message arrived on and its position in that connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageOrigin {
+    /// Unique identifier of the WebSocket connection.
+    pub connection_id: ObjectId,
+
+    /// Position of the message in the connection, starting at 1.
+    pub seq: u64,
+}
+
+/// A decoded message waiting in the channel between `enqueue` and the writer task.
+#[derive(Debug)]
+pub struct Received {
+    /// The JSON payload of the message.
+    pub value: Value,
+
+    /// When the message was received from the WebSocket.
+    pub received_at: DateTime,
+
+    /// The frame as received, dead-lettered as is if the payload, or any item of it, cannot be
+    /// stored.
+    pub frame: Arc<Message>,
+
+    /// The WebSocket frame type the payload came in, `"text"` or `"binary"`.
+    pub frame_type: &'static str,
+
+    /// The connection and sequence number of the message, if known.
+    pub origin: Option<MessageOrigin>,
+}
+
+/// Adds ingestion metadata to the documents before they are written.
+///
+/// The metadata fields are `_received_at` (BSON DateTime), `_source`, `_frame_type` and, when
+/// the origin of the message is known, `_connection_id` and `_seq`.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    /// Whether the payload is wrapped under `key` or merged with the metadata.
+    pub mode: EnvelopeMode,
+
+    /// The key holding the payload in `EnvelopeMode::Wrap`.
+    pub key: String,
+
+    /// The value stored as `_source`.
+    pub source: String,
+}
+
+impl Envelope {
+    /// Creates the envelope described by the configuration, or `None` if it is disabled.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if config.envelope_mode == EnvelopeMode::None {
+            return None;
+        }
+        Some(Envelope {
+            mode: config.envelope_mode,
+            key: config.envelope_key.clone(),
+            source: config
+                .envelope_source
+                .clone()
+                .unwrap_or_else(|| config.websocket_url.clone()),
+        })
+    }
+
+    /// Returns `document` with the metadata of the message it came from.
+    ///
+    /// In `EnvelopeMode::Merge`, metadata fields replace payload fields of the same name.
+    ///
+    /// # Arguments
+    ///
+    /// * `document` - The payload document.
+    /// * `received_at` - When the message was received.
+    /// * `frame_type` - The WebSocket frame type of the message.
+    /// * `origin` - The connection and sequence number of the message, if known.
+    pub fn apply(
+        &self,
+        document: Document,
+        received_at: DateTime,
+        frame_type: &str,
+        origin: Option<MessageOrigin>,
+    ) -> Document {
+        let mut enveloped = match self.mode {
+            EnvelopeMode::None => return document,
+            EnvelopeMode::Wrap => doc! { self.key.as_str(): document },
+            EnvelopeMode::Merge => document,
+        };
+        enveloped.insert("_received_at", received_at);
+        enveloped.insert("_source", self.source.as_str());
+        enveloped.insert("_frame_type", frame_type);
+        if let Some(origin) = origin {
+            enveloped.insert("_connection_id", origin.connection_id);
+            // BSON has no unsigned integers; a connection never gets close to i64::MAX messages
+            enveloped.insert("_seq", origin.seq as i64);
+        }
+        enveloped
+    }
+}
+
+/// Converts a JSON timestamp to a BSON date.
+///
+/// Numbers, and strings holding a number, are epoch times in `unit`. Other strings are read as
+/// RFC 3339 dates, such as `2024-05-17T09:30:00.123Z`. Sub-millisecond precision is truncated.
+///
+/// # Arguments
+///
+/// * `value` - The JSON timestamp.
+/// * `unit` - The unit of numeric timestamps.
+///
+/// # Returns
+///
+/// `None` if the value is not a timestamp or is out of the range of BSON dates.
+pub fn to_datetime(value: &Value, unit: TimeUnit) -> Option<DateTime> {
+    let number = match value {
+        Value::Number(number) => number.clone(),
+        Value::String(text) => match text.trim().parse::<serde_json::Number>() {
+            Ok(number) => number,
+            Err(_) => return DateTime::parse_rfc3339_str(text.trim()).ok(),
+        },
+        _ => return None,
+    };
+    let millis = match (number.as_i64(), unit) {
+        (Some(value), TimeUnit::Seconds) => value.checked_mul(1000)?,
+        (Some(value), TimeUnit::Millis) => value,
+        (Some(value), TimeUnit::Micros) => value.div_euclid(1000),
+        (Some(value), TimeUnit::Nanos) => value.div_euclid(1_000_000),
+        (None, unit) => {
+            let factor = match unit {
+                TimeUnit::Seconds => 1000.0,
+                TimeUnit::Millis => 1.0,
+                TimeUnit::Micros => 0.001,
+                TimeUnit::Nanos => 0.000_001,
+            };
+            let millis = (number.as_f64()? * factor).floor();
+            if !millis.is_finite() || millis.abs() > i64::MAX as f64 {
+                return None;
+            }
+            millis as i64
+        }
+    };
+    Some(DateTime::from_millis(millis))
+}
+
+/// Converts the fields of a payload document to the BSON types named by coercion rules.
+///
+/// Fields that are missing or null are left alone, as are fields that already have the target
+/// type.
+///
+/// # Arguments
+///
+/// * `document` - The payload document, converted from JSON.
+/// * `coercions` - The target types, keyed by JSON pointer. A `*` segment matches every element
+///   of an array or every field of an object.
+///
+/// # Errors
+///
+/// Returns `MongoClientError::Coerce` naming the first field that cannot be converted.
+pub fn coerce(
+    document: &mut Document,
+    coercions: &BTreeMap<String, Coercion>,
+) -> Result<(), MongoClientError> {
+    if coercions.is_empty() {
+        return Ok(());
+    }
+    let mut root = Bson::Document(std::mem::take(document));
+    let mut outcome = Ok(());
+    for (pointer, coercion) in coercions {
+        let segments: Vec<String> = pointer
+            .split('/')
+            .skip(1)
+            .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        if let Err(reason) = coerce_at(&mut root, &segments, *coercion) {
+            outcome = Err(MongoClientError::Coerce(format!(
+                "{} at {}",
+                reason, pointer
+            )));
+            break;
+        }
+    }
+    if let Bson::Document(coerced) = root {
+        *document = coerced;
+    }
+    outcome
+}
+
+/// Applies a coercion to the values reached by the remaining pointer segments.
+fn coerce_at(value: &mut Bson, segments: &[String], coercion: Coercion) -> Result<(), String> {
+    let Some((segment, rest)) = segments.split_first() else {
+        if *value != Bson::Null {
+            *value = coerce_value(value, coercion)?;
+        }
+        return Ok(());
+    };
+    match value {
+        Bson::Document(document) if segment == "*" => {
+            for (_, field) in document.iter_mut() {
+                coerce_at(field, rest, coercion)?;
+            }
+        }
+        Bson::Document(document) => {
+            if let Some(field) = document.get_mut(segment) {
+                coerce_at(field, rest, coercion)?;
+            }
+        }
+        Bson::Array(array) if segment == "*" => {
+            for element in array.iter_mut() {
+                coerce_at(element, rest, coercion)?;
+            }
+        }
+        Bson::Array(array) => {
+            let element = segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| array.get_mut(index));
+            if let Some(element) = element {
+                coerce_at(element, rest, coercion)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Converts a single value to the type of `coercion`.
+fn coerce_value(value: &Bson, coercion: Coercion) -> Result<Bson, String> {
+    let invalid = || format!("{} is not a valid {}", value, coercion);
+    let coerced = match (coercion, value) {
+        (Coercion::DateTime(_), Bson::DateTime(_))
+        | (Coercion::Decimal128, Bson::Decimal128(_))
+        | (Coercion::ObjectId, Bson::ObjectId(_))
+        | (Coercion::Int32, Bson::Int32(_))
+        | (Coercion::Int64, Bson::Int64(_)) => value.clone(),
+        (Coercion::DateTime(unit), value) => {
+            let json = value.clone().into_relaxed_extjson();
+            Bson::DateTime(to_datetime(&json, unit).ok_or_else(invalid)?)
+        }
+        (Coercion::Decimal128, value) => {
+            let text = match value {
+                Bson::Int32(number) => number.to_string(),
+                Bson::Int64(number) => number.to_string(),
+                // Display gives the shortest text that reads back as the same double
+                Bson::Double(number) if number.is_finite() => number.to_string(),
+                Bson::String(text) => text.trim().to_string(),
+                _ => return Err(invalid()),
+            };
+            Bson::Decimal128(text.parse::<Decimal128>().map_err(|_| invalid())?)
+        }
+        (Coercion::ObjectId, Bson::String(text)) => {
+            Bson::ObjectId(ObjectId::parse_str(text.trim()).map_err(|_| invalid())?)
+        }
+        (Coercion::Int32, value) => {
+            let number = integer(value).ok_or_else(invalid)?;
+            Bson::Int32(i32::try_from(number).map_err(|_| invalid())?)
+        }
+        (Coercion::Int64, value) => Bson::Int64(integer(value).ok_or_else(invalid)?),
+        _ => return Err(invalid()),
+    };
+    Ok(coerced)
+}
+
+/// Reads an integer from an integral number or a string holding one.
+fn integer(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(number) => Some(i64::from(*number)),
+        Bson::Int64(number) => Some(*number),
+        Bson::Double(number) if number.fract() == 0.0 && number.abs() < i64::MAX as f64 => {
+            Some(*number as i64)
+        }
+        Bson::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Stores documents in time-series collections.
+///
+/// Every document gets `time_field` as a BSON date, read from the payload or set to the receive
+/// time, and the target collections are created as time-series collections.
+#[derive(Debug, Clone)]
+pub struct Timeseries {
+    /// The `timeField` of the collections.
+    pub time_field: String,
+
+    /// JSON pointer into the payload holding the time, or `None` for the receive time.
+    pub time_pointer: Option<String>,
+
+    /// The unit of numeric times read at `time_pointer`.
+    pub time_unit: TimeUnit,
+
+    /// The optional `metaField` of the collections.
+    pub meta_field: Option<String>,
+
+    /// The optional granularity of the collections.
+    pub granularity: Option<Granularity>,
+
+    /// The optional age after which MongoDB deletes documents.
+    pub expire_after: Option<Duration>,
+}
+
+impl Timeseries {
+    /// Creates the time-series settings described by the configuration, or `None` if
+    /// `timeseries_time_field` is not set.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Some(Timeseries {
+            time_field: config.timeseries_time_field.clone()?,
+            time_pointer: config.timeseries_time_pointer.clone(),
+            time_unit: config.timeseries_time_unit,
+            meta_field: config.timeseries_meta_field.clone(),
+            granularity: config.timeseries_granularity,
+            expire_after: config
+                .timeseries_expire_after_seconds
+                .map(Duration::from_secs),
+        })
+    }
+
+    /// Sets `time_field` of `document` to the time of the measurement.
+    ///
+    /// # Arguments
+    ///
+    /// * `document` - The document to store.
+    /// * `payload` - The received payload, read at `time_pointer`.
+    /// * `received_at` - When the message was received.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MongoClientError::TimeField` if the payload has no valid time at `time_pointer`.
+    pub fn apply(
+        &self,
+        document: &mut Document,
+        payload: &Value,
+        received_at: DateTime,
+    ) -> Result<(), MongoClientError> {
+        let time = match &self.time_pointer {
+            None => received_at,
+            Some(pointer) => match payload.pointer(pointer) {
+                Some(value) => to_datetime(value, self.time_unit).ok_or_else(|| {
+                    MongoClientError::TimeField(format!("{} at {} is not a time", value, pointer))
+                })?,
+                None => {
+                    return Err(MongoClientError::TimeField(format!(
+                        "{} is missing",
+                        pointer
+                    )))
+                }
+            },
+        };
+        document.insert(self.time_field.as_str(), time);
+        Ok(())
+    }
+
+    /// Returns the options creating a time-series collection with these settings.
+    pub fn create_options(&self) -> CreateCollectionOptions {
+        let granularity = self.granularity.map(|granularity| match granularity {
+            Granularity::Seconds => TimeseriesGranularity::Seconds,
+            Granularity::Minutes => TimeseriesGranularity::Minutes,
+            Granularity::Hours => TimeseriesGranularity::Hours,
+        });
+        let timeseries = TimeseriesOptions::builder()
+            .time_field(self.time_field.clone())
+            .meta_field(self.meta_field.clone())
+            .granularity(granularity)
+            .build();
+        CreateCollectionOptions::builder()
+            .timeseries(timeseries)
+            .expire_after_seconds(self.expire_after)
+            .build()
+    }
+
+    /// Creates `name` as a time-series collection unless it already exists.
+    ///
+    /// An existing collection is left as is, with a warning if it is not a time-series
+    /// collection.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MongoClientError::CreateCollection` if the collection cannot be listed or
+    /// created.
+    pub async fn ensure_collection(
+        &self,
+        database: &Database,
+        name: &str,
+    ) -> Result<(), MongoClientError> {
+        let failed = |e| MongoClientError::CreateCollection(name.to_string(), e);
+        let existing: Vec<_> = database
+            .list_collections(doc! {"name": name}, None)
+            .await
+            .map_err(failed)?
+            .try_collect()
+            .await
+            .map_err(failed)?;
+        if let Some(specification) = existing.first() {
+            if specification.collection_type != CollectionType::Timeseries
+                && log_enabled(LogLevel::Warn)
+            {
+                eprintln!(
+                    "Collection {} exists and is not a time-series collection",
+                    name
+                );
+            }
+            return Ok(());
+        }
+
+        match database
+            .create_collection(name, self.create_options())
+            .await
+        {
+            Ok(()) => {
+                if log_enabled(LogLevel::Info) {
+                    println!("Created time-series collection {}", name);
+                }
+                Ok(())
+            }
+            // Another pipeline created it in the meantime
+            Err(e) if matches!(*e.kind, ErrorKind::Command(ref command) if command.code == 48) => {
+                Ok(())
+            }
+            Err(e) => Err(failed(e)),
+        }
+    }
+}
+
+/// The MongoDB error code of a duplicate key.
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+/// Identifies stored documents by a business key, for upserts and deduplication.
+#[derive(Debug, Clone)]
+pub struct WriteKey {
+    /// Whether documents are inserted, upserted or replaced.
+    pub mode: WriteMode,
+
+    /// The key fields, as dotted paths into the stored documents.
+    pub fields: Vec<String>,
+
+    /// Whether a unique index backs the key, making duplicate-key errors harmless.
+    pub unique: bool,
+}
+
+impl WriteKey {
+    /// Creates the key described by the configuration, or `None` if `write_key_fields` is
+    /// empty.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if config.write_key_fields.is_empty() {
+            return None;
+        }
+        Some(WriteKey {
+            mode: config.write_mode,
+            fields: config.write_key_fields.clone(),
+            unique: config.write_key_unique,
+        })
+    }
+
+    /// Returns the filter matching the stored document with the same key as `document`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MongoClientError::MissingKey` naming the first key field that is missing or null.
+    pub fn filter(&self, document: &Document) -> Result<Document, MongoClientError> {
+        let mut filter = Document::new();
+        for field in &self.fields {
+            let mut segments = field.split('.');
+            let first = segments.next().and_then(|segment| document.get(segment));
+            let value = segments.try_fold(first, |value, segment| match value {
+                Some(Bson::Document(nested)) => Some(nested.get(segment)),
+                _ => None,
+            });
+            match value.flatten() {
+                Some(Bson::Null) | None => return Err(MongoClientError::MissingKey(field.clone())),
+                Some(value) => filter.insert(field.as_str(), value.clone()),
+            };
+        }
+        Ok(filter)
+    }
+
+    /// Returns the unique index on the key fields.
+    pub fn index(&self) -> IndexModel {
+        let keys: Document = self
+            .fields
+            .iter()
+            .map(|field| (field.clone(), Bson::Int32(1)))
+            .collect();
+        IndexModel::builder()
+            .keys(keys)
+            .options(IndexOptions::builder().unique(true).build())
+            .build()
+    }
+}
+
+/// The MongoDB error code of a missing namespace, returned when listing the indexes of a
+/// collection that does not exist yet.
+const NAMESPACE_NOT_FOUND_CODE: i32 = 26;
+
+/// Returns the indexes declared by the configuration: `mongodb_indexes`, followed by the unique
+/// index of the write key when `write_key_unique` is set.
+pub fn declared_indexes(config: &Config) -> Vec<IndexModel> {
+    let mut indexes: Vec<IndexModel> = config
+        .mongodb_indexes
+        .iter()
+        .map(IndexSpec::model)
+        .collect();
+    if let Some(key) = WriteKey::from_config(config).filter(|key| key.unique) {
+        indexes.push(key.index());
+    }
+    indexes
+}
+
+/// The changes needed to bring the indexes of a collection in line with the declared ones.
+#[derive(Debug, Default)]
+pub struct IndexPlan {
+    /// Declared indexes missing from the collection.
+    pub create: Vec<IndexModel>,
+
+    /// Names of the existing indexes that are not declared, other than `_id_`.
+    pub undeclared: Vec<String>,
+}
+
+/// Returns the name of an index, or the name MongoDB gives it by default.
+fn index_name(index: &IndexModel) -> String {
+    index
+        .options
+        .as_ref()
+        .and_then(|options| options.name.clone())
+        .unwrap_or_else(|| default_index_name(&index.keys))
+}
+
+/// Describes the keys and options of an index, in a form where equal indexes compare equal.
+fn index_signature(index: &IndexModel) -> (Vec<(String, String)>, String) {
+    let keys = index
+        .keys
+        .iter()
+        .map(|(field, direction)| (field.clone(), index_direction(direction)))
+        .collect();
+    let options = index.options.as_ref();
+    let unique = options.and_then(|options| options.unique).unwrap_or(false);
+    let expire_after = options.and_then(|options| options.expire_after);
+    let filter = options
+        .and_then(|options| options.partial_filter_expression.clone())
+        .map(|filter| Bson::Document(filter).into_relaxed_extjson());
+    let mut described = format!("unique: {}", unique);
+    if let Some(expire_after) = expire_after {
+        described.push_str(&format!(", expire after: {}s", expire_after.as_secs()));
+    }
+    if let Some(filter) = filter {
+        described.push_str(&format!(", partial filter: {}", filter));
+    }
+    (keys, described)
+}
+
+/// Compares the existing indexes of a collection with the declared ones.
+///
+/// A declared index matches an existing index with the same name or the same keys. It is
+/// created if nothing matches.
+///
+/// # Arguments
+///
+/// * `existing` - The indexes of the collection, as listed by MongoDB.
+/// * `declared` - The indexes the collection must have.
+///
+/// # Errors
+///
+/// Returns the reason if a matching index has different keys or options than the declared one:
+/// MongoDB would refuse to create the declared index, and dropping the existing one is left to
+/// the operator.
+pub fn plan_indexes(existing: &[IndexModel], declared: &[IndexModel]) -> Result<IndexPlan, String> {
+    let mut plan = IndexPlan::default();
+    let mut matched = vec![false; existing.len()];
+    for index in declared {
+        let name = index_name(index);
+        let signature = index_signature(index);
+        let found = existing
+            .iter()
+            .position(|other| index_name(other) == name)
+            .or_else(|| {
+                existing
+                    .iter()
+                    .position(|other| index_signature(other).0 == signature.0)
+            });
+        let Some(position) = found else {
+            plan.create.push(index.clone());
+            continue;
+        };
+        let other = &existing[position];
+        let other_signature = index_signature(other);
+        if other_signature != signature {
+            return Err(format!(
+                "index {} is declared with keys {} ({}) but exists as {} with keys {} ({})",
+                name,
+                index.keys,
+                signature.1,
+                index_name(other),
+                other.keys,
+                other_signature.1
+            ));
+        }
+        matched[position] = true;
+    }
+    plan.undeclared = existing
+        .iter()
+        .zip(matched)
+        .filter(|(_, matched)| !matched)
+        .map(|(index, _)| index_name(index))
+        .filter(|name| name != "_id_")
+        .collect();
+    Ok(plan)
+}
+
+/// Creates the declared indexes missing from a collection, see `plan_indexes`. Existing indexes
+/// that are not declared are kept, with a warning.
+///
+/// # Arguments
+///
+/// * `collection` - The collection to index.
+/// * `declared` - The indexes the collection must have.
+///
+/// # Errors
+///
+/// Returns `MongoClientError::ListIndexes` if the indexes cannot be listed,
+/// `MongoClientError::IndexConflict` if an existing index conflicts with a declared one, or
+/// `MongoClientError::CreateIndex` if an index cannot be created, for instance because the
+/// collection holds duplicates of a unique key.
+pub async fn ensure_indexes(
+    collection: &Collection<Document>,
+    declared: &[IndexModel],
+) -> Result<(), MongoClientError> {
+    if declared.is_empty() {
+        return Ok(());
+    }
+    let name = collection.name().to_string();
+    let existing: Vec<IndexModel> = match collection.list_indexes(None).await {
+        Ok(cursor) => cursor
+            .try_collect()
+            .await
+            .map_err(|e| MongoClientError::ListIndexes(name.clone(), e))?,
+        Err(e) if error_code(&e) == Some(NAMESPACE_NOT_FOUND_CODE) => Vec::new(),
+        Err(e) => return Err(MongoClientError::ListIndexes(name, e)),
+    };
+    let plan = plan_indexes(&existing, declared)
+        .map_err(|reason| MongoClientError::IndexConflict(name.clone(), reason))?;
+    if !plan.undeclared.is_empty() && log_enabled(LogLevel::Warn) {
+        eprintln!(
+            "Warning: collection {} has undeclared indexes: {}",
+            name,
+            plan.undeclared.join(", ")
+        );
+    }
+    for index in plan.create {
+        let index_name = index_name(&index);
+        collection
+            .create_index(index, None)
+            .await
+            .map_err(|e| MongoClientError::CreateIndex(name.clone(), index_name.clone(), e))?;
+        if log_enabled(LogLevel::Info) {
+            println!("Created index {} on {}", index_name, name);
+        }
+    }
+    Ok(())
+}
+
+/// Builds the dead-letter entry for a rejected message.
+///
+/// Text payloads are stored as is and binary payloads are base64-encoded; `payload_encoding`
+/// tells which one was used.
+///
+/// # Arguments
+///
+/// * `message` - The rejected message.
+/// * `error` - The reason it was rejected.
+/// * `source` - Where the message came from, usually the WebSocket URL.
+/// * `received_at` - When the message was received.
+pub fn dead_letter_document(
+    message: &Message,
+    error: &MongoClientError,
+    source: &str,
+    received_at: DateTime,
+) -> Document {
+    let (payload, encoding) = match message {
+        Message::Text(text) => (text.clone(), "text"),
+        Message::Binary(data) => (BASE64.encode(data), "base64"),
+        other => (format!("{:?}", other), "debug"),
+    };
+    doc! {
+        "payload": payload,
+        "payload_encoding": encoding,
+        "error_kind": error.kind(),
+        "error": error.to_string(),
+        "source": source,
+        "received_at": received_at,
+        "dead_lettered_at": DateTime::now(),
+    }
+}
+
+/// Returns the collection the first matching rule picks for `payload`.
+///
+/// # Arguments
+///
+/// * `routes` - The routing rules, in order.
+/// * `payload` - The received payload.
+///
+/// # Returns
+///
+/// `None` when no rule matches, meaning the document goes to `collection_name`.
+pub fn route_collection(routes: &[CollectionRoute], payload: &Value) -> Option<String> {
+    routes.iter().find_map(|route| route.collection(payload))
+}
+
+/// Returns the collection the first matching rule picks for `payload`, checked against the
+/// MongoDB naming rules, as names built from payload fields can be anything.
+///
+/// # Arguments
+///
+/// * `routes` - The routing rules, in order.
+/// * `database` - The name of the database holding the routed collections.
+/// * `payload` - The received payload.
+///
+/// # Errors
+///
+/// Returns `MongoClientError::InvalidCollection` if the name is empty, contains `$` or NUL,
+/// starts with `system.` or is too long.
+pub fn checked_route_collection(
+    routes: &[CollectionRoute],
+    database: &str,
+    payload: &Value,
+) -> Result<Option<String>, MongoClientError> {
+    match route_collection(routes, payload) {
+        Some(name) => match check_collection_name(database, &name) {
+            Ok(()) => Ok(Some(name)),
+            Err(reason) => Err(MongoClientError::InvalidCollection(name, reason)),
+        },
+        None => Ok(None),
+    }
+}
+
 /// Generates a MongoDB error with the given message.
 ///
 /// This function is used to generate a custom MongoDB error. It takes a `&str` parameter `message`
@@ -50,10 +903,109 @@ use mongodb::{
 ///
 /// The generated `MongoError` containing the provided error message.
 fn generate_mongo_error(message: &str) -> MongoError {
-    MongoError::from(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        message.to_string(),
-    ))
+    MongoError::from(std::io::Error::other(message.to_string()))
+}
+
+/// Builds the MongoDB credential described by `config`.
+///
+/// `MONGODB-AWS` needs the `aws-auth` cargo feature. It authenticates against `$external` and
+/// takes its `AWS_SESSION_TOKEN` mechanism property from `mongodb_aws_session_token`.
+///
+/// # Arguments
+///
+/// * `config` - The configuration holding the MongoDB user, password and mechanism.
+///
+/// # Returns
+///
+/// `None` when no user is set, unless the mechanism is `MONGODB-AWS`, which can take its
+/// credentials from the AWS environment, or `MONGODB-X509`, which takes the user from the client
+/// certificate.
+///
+/// # Errors
+///
+/// Returns `MongoClientError::UnsupportedAuthMechanism` for a mechanism that is not supported,
+/// such as `GSSAPI`, or `MONGODB-AWS` without its cargo feature.
+pub fn credential(config: &Config) -> Result<Option<Credential>, MongoClientError> {
+    let mechanism = config.mongodb_auth_mechanism.as_str();
+    if config.mongodb_user.is_none()
+        && mechanism != MECHANISM_MONGODB_AWS
+        && mechanism != MECHANISM_MONGODB_X509
+    {
+        return Ok(None);
+    }
+
+    let mut credential = Credential::default();
+    credential.username = config.mongodb_user.clone();
+    credential.password = config
+        .mongodb_password
+        .as_ref()
+        .map(|password| password.expose().to_string());
+    credential.source = Some(config.mongodb_auth_source.clone());
+    credential.mechanism = match mechanism {
+        MECHANISM_SCRAM_SHA_1 => Some(AuthMechanism::ScramSha1),
+        MECHANISM_SCRAM_SHA_256 => Some(AuthMechanism::ScramSha256),
+        MECHANISM_MONGODB_CR => Some(AuthMechanism::MongoDbCr),
+        MECHANISM_MONGODB_X509 => {
+            credential.source = None; // The driver uses $external
+            Some(AuthMechanism::MongoDbX509)
+        }
+        MECHANISM_PLAIN => Some(AuthMechanism::Plain),
+        #[cfg(feature = "aws-auth")]
+        MECHANISM_MONGODB_AWS => {
+            credential.source = None; // The driver uses $external
+            if let Some(token) = &config.mongodb_aws_session_token {
+                credential.mechanism_properties = Some(doc! {"AWS_SESSION_TOKEN": token.expose()});
+            }
+            Some(AuthMechanism::MongoDbAws)
+        }
+        mechanism => {
+            return Err(MongoClientError::UnsupportedAuthMechanism(
+                mechanism.to_string(),
+            ))
+        }
+    };
+    Ok(Some(credential))
+}
+
+/// Builds the TLS settings of the MongoDB connection from `config`.
+///
+/// The files and flags of `config` override the ones parsed from `MONGODB_URI`. Setting any of
+/// them turns TLS on, unless `mongodb_tls` is `false`.
+///
+/// # Arguments
+///
+/// * `config` - The configuration holding the MongoDB TLS settings.
+/// * `uri_tls` - The TLS settings parsed from `MONGODB_URI`.
+///
+/// # Returns
+///
+/// The TLS settings to connect with, or `uri_tls` when `config` sets none.
+pub fn tls(config: &Config, uri_tls: Option<Tls>) -> Option<Tls> {
+    if config.mongodb_tls == Some(false) {
+        return Some(Tls::Disabled);
+    }
+    let overridden = config.mongodb_tls_ca_file.is_some()
+        || config.mongodb_tls_cert_key_file.is_some()
+        || config.mongodb_tls_allow_invalid_hostnames;
+    if config.mongodb_tls.is_none() && !overridden {
+        return uri_tls;
+    }
+
+    let mut options = match uri_tls {
+        Some(Tls::Enabled(options)) => options,
+        _ => TlsOptions::default(),
+    };
+    if let Some(path) = &config.mongodb_tls_ca_file {
+        options.ca_file_path = Some(path.into());
+    }
+    if let Some(path) = &config.mongodb_tls_cert_key_file {
+        options.cert_key_file_path = Some(path.into());
+    }
+    #[cfg(feature = "openssl-tls")]
+    if config.mongodb_tls_allow_invalid_hostnames {
+        options.allow_invalid_hostnames = Some(true);
+    }
+    Some(Tls::Enabled(options))
 }
 
 /// Test the connection to MongoDB.
@@ -74,22 +1026,194 @@ pub async fn test_mongo_connection(client: &Client, db: &str) -> MongoResult<()>
     let command = doc! {"ping": 1};
     let result = database.run_command(command, None).await?;
 
-    if let Ok(ok) = result.get_f64("ok") {
-        if ok == 1.0 {
-            println!("Successfully connected to MongoDB.");
-            return Ok(());
+    // Failures are returned, the caller decides whether and how to report them
+    match result.get_f64("ok") {
+        Ok(1.0) => {
+            if log_enabled(LogLevel::Info) {
+                println!("Successfully connected to MongoDB.");
+            }
+            Ok(())
         }
-        println!("Received an unexpected response from MongoDB.");
-        return Err(generate_mongo_error("Unexpected response to ping"));
+        Ok(ok) => Err(generate_mongo_error(&format!(
+            "unexpected response to ping: ok = {}",
+            ok
+        ))),
+        Err(e) => Err(generate_mongo_error(&format!(
+            "failed to retrieve 'ok' from the ping response: {}",
+            e
+        ))),
     }
+}
 
-    // Error handling, separate match clause
-    match result.get_f64("ok") {
-        Err(e) => {
-            println!("Failed to retrieve 'ok' from response: {}", e);
-            Err(generate_mongo_error(&format!("{}", e)))
+/// Prepares a collection before it is written to: creates it as a time-series collection, when
+/// configured, and ensures the declared indexes.
+///
+/// # Errors
+///
+/// Returns `MongoClientError::CreateCollection` or any error of `ensure_indexes`.
+async fn prepare_collection(
+    database: &Database,
+    name: &str,
+    timeseries: Option<&Timeseries>,
+    indexes: &[IndexModel],
+) -> Result<(), MongoClientError> {
+    if let Some(timeseries) = timeseries {
+        timeseries.ensure_collection(database, name).await?;
+    }
+    ensure_indexes(&database.collection(name), indexes).await
+}
+
+/// Returns the server error code of a failed write or command, if there is one.
+fn error_code(error: &MongoError) -> Option<i32> {
+    match error.kind.as_ref() {
+        ErrorKind::Command(command) => Some(command.code),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) => Some(write_error.code),
+        _ => None,
+    }
+}
+
+/// Returns the documents of a failed `insert_many` call, as `(index, code, reason)` triples.
+///
+/// Bulk write failures report the offending documents individually. Any other error (network,
+/// write concern, ...) leaves the outcome of every document unknown, so all of them are reported.
+///
+/// # Arguments
+///
+/// * `error` - The error returned by `insert_many`.
+/// * `batch_len` - The number of documents sent in the batch.
+pub fn failed_documents(error: &MongoError, batch_len: usize) -> Vec<(usize, Option<i32>, String)> {
+    if let ErrorKind::BulkWrite(failure) = error.kind.as_ref() {
+        if let Some(write_errors) = &failure.write_errors {
+            return write_errors
+                .iter()
+                .map(|write_error| {
+                    (
+                        write_error.index,
+                        Some(write_error.code),
+                        write_error.message.clone(),
+                    )
+                })
+                .collect();
+        }
+    }
+    let code = error_code(error);
+    (0..batch_len)
+        .map(|index| (index, code, error.to_string()))
+        .collect()
+}
+
+/// A batched document with the receive time and the raw frame of its message.
+pub type BatchEntry = (Document, DateTime, Arc<Message>);
+
+/// The documents of a batch bound to one collection, `None` being the default collection, with
+/// their receive times and frames.
+pub type CollectionEntries = (Option<String>, Vec<BatchEntry>);
+
+/// A buffer of documents waiting to be written to MongoDB with one `insert_many` per collection.
+///
+/// A batch is due when it reaches its maximum size or when its oldest document has waited
+/// longer than the batch timeout. Every document keeps the time its message was received, the
+/// raw frame of that message and the collection it was routed to, if any.
+#[derive(Debug)]
+pub struct DocumentBatch {
+    documents: Vec<BatchEntry>,
+    collections: Vec<Option<String>>,
+    max_size: usize,
+    timeout: Duration,
+    started_at: Option<Instant>,
+}
+
+impl DocumentBatch {
+    /// Creates an empty batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_size` - The number of documents that makes the batch full (at least 1).
+    /// * `timeout` - The maximum time the first document of the batch may wait.
+    pub fn new(max_size: usize, timeout: Duration) -> Self {
+        let max_size = max_size.max(1);
+        DocumentBatch {
+            documents: Vec::with_capacity(max_size),
+            collections: Vec::with_capacity(max_size),
+            max_size,
+            timeout,
+            started_at: None,
+        }
+    }
+
+    /// Adds a document to the batch, starting the timeout clock if the batch was empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `document` - The document to write.
+    /// * `received_at` - When the message holding the document was received.
+    /// * `frame` - The raw frame of that message.
+    pub fn push(&mut self, document: Document, received_at: DateTime, frame: Arc<Message>) {
+        self.push_to(None, document, received_at, frame);
+    }
+
+    /// Adds a document bound to a routed collection.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The collection picked by the routing rules, or `None` for the default.
+    /// * `document` - The document to write.
+    /// * `received_at` - When the message holding the document was received.
+    /// * `frame` - The raw frame of that message.
+    pub fn push_to(
+        &mut self,
+        collection: Option<String>,
+        document: Document,
+        received_at: DateTime,
+        frame: Arc<Message>,
+    ) {
+        if self.documents.is_empty() {
+            self.started_at = Some(Instant::now());
         }
-        _ => unreachable!(), // This case should've been handled above
+        self.documents.push((document, received_at, frame));
+        self.collections.push(collection);
+    }
+
+    /// Returns the number of buffered documents.
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    /// Returns `true` if the batch holds no documents.
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Returns `true` if the batch reached its maximum size.
+    pub fn is_full(&self) -> bool {
+        self.documents.len() >= self.max_size
+    }
+
+    /// Returns the instant at which the batch must be flushed, or `None` if it is empty.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.started_at.map(|started_at| started_at + self.timeout)
+    }
+
+    /// Empties the batch and returns its documents with their receive times and frames, whatever
+    /// their collection.
+    pub fn take(&mut self) -> Vec<BatchEntry> {
+        self.started_at = None;
+        self.collections.clear();
+        std::mem::replace(&mut self.documents, Vec::with_capacity(self.max_size))
+    }
+
+    /// Empties the batch and returns its documents grouped by collection, in the order each
+    /// collection first appeared. Documents keep their order within a group.
+    pub fn take_by_collection(&mut self) -> Vec<CollectionEntries> {
+        let collections = std::mem::take(&mut self.collections);
+        let mut groups: Vec<CollectionEntries> = Vec::new();
+        for (collection, entry) in collections.into_iter().zip(self.take()) {
+            match groups.iter_mut().find(|(name, _)| *name == collection) {
+                Some((_, entries)) => entries.push(entry),
+                None => groups.push((collection, vec![entry])),
+            }
+        }
+        groups
     }
 }
 
@@ -98,11 +1222,50 @@ pub struct MongoClient {
     /// The MongoDB collection to interact with.
     collection: Collection<Document>,
 
+    /// The database holding the routed collections.
+    database: Database,
+
+    /// Rules choosing another collection than `collection` for a document.
+    routes: Vec<CollectionRoute>,
+
+    /// Handles of the routed collections, created the first time a document is routed to them.
+    routed_collections: Mutex<HashMap<String, Collection<Document>>>,
+
+    /// The collection receiving messages rejected under `ErrorPolicy::DeadLetter`.
+    dead_letter_collection: Collection<Document>,
+
+    /// The source recorded in dead-letter entries, the WebSocket URL.
+    source: String,
+
+    /// What the writer task does with documents it cannot write.
+    write_error_policy: ErrorPolicy,
+
+    /// Optional ingestion metadata added to every document.
+    envelope: Option<Envelope>,
+
+    /// BSON types the payload fields are converted to, keyed by JSON pointer.
+    coercions: BTreeMap<String, Coercion>,
+
+    /// Optional time-series settings of the target collections.
+    timeseries: Option<Timeseries>,
+
+    /// Optional business key of the documents, for keyed writes and deduplication.
+    key: Option<WriteKey>,
+
+    /// Indexes ensured on the routed collections, see `declared_indexes`.
+    indexes: Vec<IndexModel>,
+
+    /// Maximum number of documents written with a single `insert_many`.
+    batch_size: usize,
+
+    /// Maximum time a non-empty batch waits before being written.
+    batch_timeout: Duration,
+
     /// The sender part of the channel for sending JSON values.
-    sender: Sender<Value>,
+    sender: Sender<Received>,
 
     /// The receiver part of the channel for receiving JSON values, wrapped in an `Arc` and `Mutex`.
-    receiver: Arc<Mutex<Receiver<Value>>>,
+    receiver: Arc<Mutex<Receiver<Received>>>,
 }
 
 impl MongoClient {
@@ -114,46 +1277,109 @@ impl MongoClient {
     ///
     /// # Returns
     ///
-    /// * `Result<Arc<Self>, Box<dyn Error>>` - Returns an `Arc` containing the new `MongoClient` instance, or an error if the connection fails.
-    pub async fn new(config: Config) -> Result<Arc<Self>, Box<dyn Error>> {
-        let mut client_options = ClientOptions::parse(&config.mongodb_uri).await?;
-        let auth_source_str: &str = config.mongodb_auth_source.as_deref().unwrap_or("admin");
-
-        if let Some(user) = config.mongodb_user {
-            let mut credential = mongodb::options::Credential::default();
-            credential.username = Some(user);
-            credential.password = config.mongodb_password;
-            credential.source = Some(auth_source_str.to_string());
-            if let Some(mech) = &config.mongodb_auth_mechanism {
-                credential.mechanism = match mech.as_str() {
-                    MECHANISM_SCRAM_SHA_1 => Some(AuthMechanism::ScramSha1),
-                    MECHANISM_SCRAM_SHA_256 => Some(AuthMechanism::ScramSha256),
-                    MECHANISM_MONGODB_CR => Some(AuthMechanism::MongoDbCr),
-                    // "MONGODB_AWS" => Some(AuthMechanism::MongoDbAws),
-                    MECHANISM_MONGODB_X509 => Some(AuthMechanism::MongoDbX509),
-                    MECHANISM_PLAIN => Some(AuthMechanism::Plain),
-                    mechanism => {
-                        return Err(format!("Unsupported auth mechanism: {}", mechanism).into())
-                    }
-                };
-            }
+    /// * `Result<Arc<Self>, MongoClientError>` - Returns an `Arc` containing the new `MongoClient` instance, or an error if the connection fails.
+    pub async fn new(config: Config) -> Result<Arc<Self>, MongoClientError> {
+        let client = Self::connect(&config).await?;
+        Self::prepare(&client, &config).await?;
+        Ok(Self::with_client(&client, &config))
+    }
 
+    /// Prepares the target collection: creates it as a time-series collection, if `config`
+    /// asks for one and it does not exist yet, and ensures the indexes of `mongodb_indexes` and
+    /// the unique index on the write key. Routed collections are prepared when first written to.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The MongoDB client to prepare the collection with.
+    /// * `config` - The configuration naming the database, collection, time-series settings,
+    ///   indexes and write key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MongoClientError::CreateCollection` if the collection cannot be created,
+    /// `MongoClientError::IndexConflict` if an existing index conflicts with a declared one, or
+    /// `MongoClientError::ListIndexes` or `MongoClientError::CreateIndex` if the indexes cannot
+    /// be listed or created.
+    pub async fn prepare(client: &Client, config: &Config) -> Result<(), MongoClientError> {
+        let database = client.database(&config.database_name);
+        prepare_collection(
+            &database,
+            &config.collection_name,
+            Timeseries::from_config(config).as_ref(),
+            &declared_indexes(config),
+        )
+        .await
+    }
+
+    /// Builds a MongoDB client from the URI, credentials and TLS settings of `config` and checks
+    /// the connection.
+    ///
+    /// The returned client owns a connection pool and can be shared by several `MongoClient`
+    /// instances through `with_client`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration options for the MongoDB client.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MongoClientError::Options` for an invalid URI,
+    /// `MongoClientError::UnsupportedAuthMechanism` for an unknown or disabled mechanism and
+    /// `MongoClientError::Connect` if the server cannot be reached.
+    pub async fn connect(config: &Config) -> Result<Client, MongoClientError> {
+        let mut client_options = ClientOptions::parse(config.mongodb_uri.expose())
+            .await
+            .map_err(MongoClientError::Options)?;
+        let auth_source_str: &str = &config.mongodb_auth_source;
+        if let Some(credential) = credential(config)? {
             client_options.credential = Some(credential);
         }
+        client_options.tls = tls(config, client_options.tls.take());
 
-        let client = Client::with_options(client_options)?;
+        let client = Client::with_options(client_options).map_err(MongoClientError::Options)?;
 
-        if let Err(_e) = test_mongo_connection(&client, auth_source_str).await {
-            return Err("Error connecting to MongoDB".into());
-        }
+        test_mongo_connection(&client, auth_source_str)
+            .await
+            .map_err(MongoClientError::Connect)?;
 
+        Ok(client)
+    }
+
+    /// Creates a new instance of `MongoClient` on top of an existing MongoDB client.
+    ///
+    /// No connection check is performed; the writer task is spawned and documents are written
+    /// as soon as they are enqueued.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The MongoDB client to write with.
+    /// * `config` - The configuration options for the MongoDB client.
+    ///
+    /// # Returns
+    ///
+    /// * `Arc<Self>` - An `Arc` containing the new `MongoClient` instance.
+    pub fn with_client(client: &Client, config: &Config) -> Arc<Self> {
         let db = client.database(&config.database_name);
         let collection = db.collection(&config.collection_name);
+        let dead_letter_collection = db.collection(&config.dead_letter_collection_name());
 
         let (sender, receiver) = mpsc::channel(100); // Buffer size of 100
 
         let instance = Arc::new(MongoClient {
             collection,
+            database: db,
+            routes: config.collection_routes.clone(),
+            routed_collections: Mutex::new(HashMap::new()),
+            dead_letter_collection,
+            source: config.websocket_url.clone(),
+            write_error_policy: config.write_error_policy,
+            envelope: Envelope::from_config(config),
+            coercions: config.type_coercions.clone(),
+            timeseries: Timeseries::from_config(config),
+            key: WriteKey::from_config(config),
+            indexes: declared_indexes(config),
+            batch_size: config.mongodb_batch_size,
+            batch_timeout: Duration::from_millis(config.mongodb_batch_timeout_ms),
             sender,
             receiver: Arc::new(Mutex::new(receiver)),
         });
@@ -163,106 +1389,438 @@ impl MongoClient {
             instance_clone.start().await;
         });
 
-        Ok(instance)
+        instance
     }
 
     /// Starts the MongoDB client to process incoming JSON messages and insert them into the database.
+    ///
+    /// Documents are gathered into a batch that is written with `insert_many` once it reaches
+    /// `mongodb_batch_size` documents or its first document has waited `mongodb_batch_timeout_ms`.
+    /// Values that cannot be written are handled according to `write_error_policy`; under
+    /// `ErrorPolicy::Abort` the channel is closed and the writer stops.
     pub async fn start(&self) {
         let receiver = Arc::clone(&self.receiver);
         let mut receiver = receiver.lock().await;
+        let mut batch = DocumentBatch::new(self.batch_size, self.batch_timeout);
 
-        while let Some(json_value) = receiver.recv().await {
-            match json_value {
-                Value::Object(_) => {
-                    // Directly try to convert the Value to a Document
-                    let document = match serde_json::from_value::<Document>(json_value) {
-                        Ok(document) => document,
-                        Err(e) => {
-                            eprintln!("Error converting JSON to a document: {}", e);
-                            continue;
+        loop {
+            let received = match batch.deadline() {
+                Some(deadline) => match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        // The batch timed out before filling up
+                        if self.flush(&mut batch).await.is_err() {
+                            break;
                         }
-                    };
-
-                    // Insert the document into MongoDB
-                    if let Err(e) = self.collection.insert_one(document, None).await {
-                        eprintln!("Error inserting document into MongoDB: {}", e);
+                        continue;
                     }
                 },
+                None => receiver.recv().await,
+            };
+
+            let Some(mut received) = received else {
+                // The channel is closed, write whatever is left
+                let _ = self.flush(&mut batch).await;
+                return;
+            };
+
+            let outcome = match received.value.take() {
                 Value::Array(array) => {
-                    // Iterate over each item in the array, assuming each item is an object
+                    // Every item of the array is stored as its own document
+                    let mut outcome = Ok(());
                     for item in array {
-                        let document = match serde_json::from_value::<Document>(item) {
-                            Ok(document) => document,
-                            Err(e) => {
-                                eprintln!("Error converting JSON item to a document: {}", e);
-                                continue;
-                            }
-                        };
-
-                        // Insert each document into MongoDB
-                        if let Err(e) = self.collection.insert_one(document, None).await {
-                            eprintln!("Error inserting document into MongoDB: {}", e);
+                        outcome = self.push(&mut batch, item, &received).await;
+                        if outcome.is_ok() && batch.is_full() {
+                            outcome = self.flush(&mut batch).await;
+                        }
+                        if outcome.is_err() {
+                            break;
                         }
                     }
-                },
-                _ => eprintln!("Received JSON is neither an object nor an array"),
+                    outcome
+                }
+                value => self.push(&mut batch, value, &received).await,
+            };
+            if outcome.is_err() || (batch.is_full() && self.flush(&mut batch).await.is_err()) {
+                break;
             }
         }
 
+        eprintln!("MongoDB writer stopped by the write error policy");
+        receiver.close();
     }
-    /// Enqueues a message to be processed by the MongoDB client.
+
+    /// Converts a JSON value to a document, wraps it in the envelope and adds it to the batch,
+    /// bound to the collection picked by the routing rules. Values routed to an invalid
+    /// collection name are rejected.
     ///
-    /// # Arguments
+    /// # Errors
     ///
-    /// * `message` - The message to be enqueued.
+    /// Returns the rejection if the value cannot be converted and the policy is
+    /// `ErrorPolicy::Abort`.
+    async fn push(
+        &self,
+        batch: &mut DocumentBatch,
+        value: Value,
+        received: &Received,
+    ) -> Result<(), MongoClientError> {
+        let error = match value {
+            Value::Object(_) => {
+                let routed = checked_route_collection(&self.routes, self.database.name(), &value)
+                    .and_then(|collection| Ok((collection, self.document(&value, received)?)));
+                match routed {
+                    Ok((collection, document)) => {
+                        batch.push_to(
+                            collection,
+                            document,
+                            received.received_at,
+                            Arc::clone(&received.frame),
+                        );
+                        return Ok(());
+                    }
+                    Err(e) => e,
+                }
+            }
+            ref scalar => MongoClientError::NotADocument(scalar.to_string()),
+        };
+        self.reject(&received.frame, error, received.received_at)
+            .await
+    }
+
+    /// Builds the document stored for a JSON object: the converted and coerced payload, wrapped
+    /// in the envelope, with the time-series time. Documents without their key are refused.
+    fn document(&self, value: &Value, received: &Received) -> Result<Document, MongoClientError> {
+        let mut document = mongodb::bson::to_document(value).map_err(MongoClientError::Convert)?;
+        coerce(&mut document, &self.coercions)?;
+        let mut document = match &self.envelope {
+            Some(envelope) => envelope.apply(
+                document,
+                received.received_at,
+                received.frame_type,
+                received.origin,
+            ),
+            None => document,
+        };
+        if let Some(timeseries) = &self.timeseries {
+            timeseries.apply(&mut document, value, received.received_at)?;
+        }
+        if let Some(key) = &self.key {
+            key.filter(&document)?;
+        }
+        Ok(document)
+    }
+
+    /// Writes the buffered documents with an unordered `insert_many` per collection and empties
+    /// the batch.
     ///
-    /// # Returns
+    /// Documents rejected by MongoDB are handled one by one according to `write_error_policy`;
+    /// the rest of the batch is still written because the inserts are unordered. So are the
+    /// documents of a routed collection that cannot be prepared, for instance because of an index
+    /// conflict.
     ///
-    /// * `Result<(), Box<dyn Error>>` - Returns `Ok(())` if the message is successfully enqueued, otherwise returns an error.
-    pub async fn enqueue(&self, message: Message) -> Result<(), Box<dyn Error>> {
-        match message {
-            Message::Text(text) => {
-                match serde_json::from_str::<Value>(&text) {
-                    Ok(json) => {
-                        // if the JSON is successfully parsed, send it to the sender
-                        self.sender.send(json).await.map_err(|e| Box::new(e) as _)
-                    }
-                    Err(_) => {
-                        // if the JSON is not successfully parsed, continue.
-                        Ok(())
+    /// # Errors
+    ///
+    /// Returns the first rejection if the policy is `ErrorPolicy::Abort`.
+    async fn flush(&self, batch: &mut DocumentBatch) -> Result<(), MongoClientError> {
+        let mut outcome = Ok(());
+        for (collection, entries) in batch.take_by_collection() {
+            let collection = match collection {
+                Some(name) => match self.routed_collection(name).await {
+                    Ok(collection) => collection,
+                    Err(e) => {
+                        // Writing without the declared indexes could let duplicates in
+                        let reason = e.to_string();
+                        for (_, received_at, frame) in &entries {
+                            let error = MongoClientError::CollectionNotReady(reason.clone());
+                            let rejected = self.reject(frame, error, *received_at).await;
+                            if outcome.is_ok() {
+                                outcome = rejected;
+                            }
+                        }
+                        continue;
                     }
+                },
+                None => self.collection.clone(),
+            };
+            let written = match &self.key {
+                Some(key) if key.mode != WriteMode::Insert => {
+                    self.upsert_entries(&collection, key, entries).await
                 }
+                _ => self.insert_entries(&collection, entries).await,
+            };
+            if outcome.is_ok() {
+                outcome = written;
             }
-            Message::Binary(data) => {
-                match serde_json::from_slice::<Value>(&data) {
-                    Ok(json) => {
-                        // if the JSON is successfully parsed, send it to the sender
-                        self.sender.send(json).await.map_err(|e| Box::new(e) as _)
-                    }
-                    Err(_) => {
-                        // if the JSON is not successfully parsed, continue.
-                        Ok(())
-                    }
+        }
+        outcome
+    }
+
+    /// Returns the handle of a routed collection, creating it on first use. The collection is
+    /// prepared like the target collection, see `MongoClient::prepare`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `prepare_collection`. The collection is not remembered in that case,
+    /// so the next document routed to it retries the preparation.
+    async fn routed_collection(
+        &self,
+        name: String,
+    ) -> Result<Collection<Document>, MongoClientError> {
+        let mut collections = self.routed_collections.lock().await;
+        if let Some(collection) = collections.get(&name) {
+            return Ok(collection.clone());
+        }
+        prepare_collection(
+            &self.database,
+            &name,
+            self.timeseries.as_ref(),
+            &self.indexes,
+        )
+        .await?;
+        let collection = self.database.collection(&name);
+        collections.insert(name, collection.clone());
+        Ok(collection)
+    }
+
+    /// Inserts the documents of one collection and applies `write_error_policy` to the ones
+    /// MongoDB rejects.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first rejection if the policy is `ErrorPolicy::Abort`.
+    async fn insert_entries(
+        &self,
+        collection: &Collection<Document>,
+        entries: Vec<BatchEntry>,
+    ) -> Result<(), MongoClientError> {
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let documents = entries.iter().map(|(document, _, _)| document);
+        let Err(e) = collection.insert_many(documents, options).await else {
+            return Ok(());
+        };
+        let failures = failed_documents(&e, entries.len());
+        self.reject_failures(&entries, failures).await
+    }
+
+    /// Upserts or replaces the documents of one collection by their key, one request per
+    /// document so that updates of the same key are applied in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first rejection if the policy is `ErrorPolicy::Abort`.
+    async fn upsert_entries(
+        &self,
+        collection: &Collection<Document>,
+        key: &WriteKey,
+        entries: Vec<BatchEntry>,
+    ) -> Result<(), MongoClientError> {
+        let mut failures = Vec::new();
+        for (index, (document, _, _)) in entries.iter().enumerate() {
+            let filter = match key.filter(document) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    failures.push((index, None, e.to_string()));
+                    continue;
+                }
+            };
+            // The _id of an existing document cannot change
+            let mut fields = document.clone();
+            fields.remove("_id");
+            let written = match key.mode {
+                WriteMode::Replace => {
+                    let options = ReplaceOptions::builder().upsert(true).build();
+                    collection
+                        .replace_one(filter, fields, options)
+                        .await
+                        .map(|_| ())
                 }
+                _ => {
+                    let options = UpdateOptions::builder().upsert(true).build();
+                    let update = doc! {"$set": fields};
+                    collection
+                        .update_one(filter, update, options)
+                        .await
+                        .map(|_| ())
+                }
+            };
+            if let Err(e) = written {
+                failures.push((index, error_code(&e), e.to_string()));
             }
+        }
+        self.reject_failures(&entries, failures).await
+    }
 
-            Message::Ping(ping_data) => {
-                println!("Ping: {:?}", ping_data);
-                Ok(())
+    /// Applies `write_error_policy` to the documents MongoDB rejected. Duplicate-key errors
+    /// count as written when the write key is backed by a unique index.
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The documents that were sent, with their receive times and frames.
+    /// * `failures` - The rejected documents, as `(index, code, reason)` triples.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first rejection if the policy is `ErrorPolicy::Abort`.
+    async fn reject_failures(
+        &self,
+        entries: &[BatchEntry],
+        failures: Vec<(usize, Option<i32>, String)>,
+    ) -> Result<(), MongoClientError> {
+        let ignore_duplicates = self.key.as_ref().is_some_and(|key| key.unique);
+        let mut outcome = Ok(());
+        for (index, code, reason) in failures {
+            if ignore_duplicates && code == Some(DUPLICATE_KEY_CODE) {
+                continue;
+            }
+            let Some((_, received_at, frame)) = entries.get(index) else {
+                // Not a document of this batch, so there is no payload to apply the policy to
+                eprintln!(
+                    "MongoDB reported a failure for unknown document {}: {}",
+                    index, reason
+                );
+                continue;
+            };
+            let rejected = self
+                .reject(frame, MongoClientError::Insert(reason), *received_at)
+                .await;
+            if outcome.is_ok() {
+                outcome = rejected;
             }
-            Message::Pong(pong_data) => {
-                println!("Pong: {:?}", pong_data);
+        }
+        outcome
+    }
+
+    /// Applies `write_error_policy` to the raw frame of a payload the writer task could not store.
+    ///
+    /// # Errors
+    ///
+    /// Returns `error` back if the policy is `ErrorPolicy::Abort`.
+    async fn reject(
+        &self,
+        payload: &Message,
+        error: MongoClientError,
+        received_at: DateTime,
+    ) -> Result<(), MongoClientError> {
+        match self.write_error_policy {
+            ErrorPolicy::Skip => Ok(()),
+            ErrorPolicy::Log => {
+                eprintln!("Error writing to MongoDB: {}: {}", error, payload);
                 Ok(())
             }
-            Message::Close(close_frame) => {
-                if let Some(frame) = close_frame {
-                    println!("Close: code={}, reason={}", frame.code, frame.reason);
-                } else {
-                    println!("Close: no details");
+            ErrorPolicy::DeadLetter => {
+                let document = dead_letter_document(payload, &error, &self.source, received_at);
+                if let Err(e) = self.insert_dead_letter(document).await {
+                    eprintln!("Error storing dead letter ({}): {}", error, e);
                 }
                 Ok(())
             }
-            _ => Err("Unsupported message format".into()),
+            ErrorPolicy::Abort => {
+                eprintln!("Error writing to MongoDB: {}: {}", error, payload);
+                Err(error)
+            }
         }
     }
+
+    /// Enqueues a message to be processed by the MongoDB client.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to be enqueued.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), MongoClientError>` - Returns `Ok(())` if the message is successfully enqueued, otherwise returns an error.
+    ///
+    /// The message is stamped with the current time as its receive time. Use `enqueue_from` to
+    /// also record the connection it arrived on.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MongoClientError::Decode` if a text or binary frame is not valid JSON or the frame
+    /// type is not supported, and `MongoClientError::Enqueue` if the writer task is gone.
+    pub async fn enqueue(&self, message: Message) -> Result<(), MongoClientError> {
+        self.enqueue_from(message, None).await
+    }
+
+    /// Enqueues a message received on a known connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to be enqueued, possibly shared with the caller so that it can
+    ///   still dead-letter it without a copy.
+    /// * `origin` - The connection and sequence number of the message, stored in the envelope.
+    ///
+    /// # Errors
+    ///
+    /// Same as `enqueue`.
+    pub async fn enqueue_from(
+        &self,
+        message: impl Into<Arc<Message>>,
+        origin: Option<MessageOrigin>,
+    ) -> Result<(), MongoClientError> {
+        let message = message.into();
+        let received_at = DateTime::now();
+        let (value, frame_type) = match &*message {
+            Message::Text(text) => (
+                serde_json::from_str::<Value>(text).map_err(DecodeError::from)?,
+                "text",
+            ),
+            Message::Binary(data) => (
+                serde_json::from_slice::<Value>(data).map_err(DecodeError::from)?,
+                "binary",
+            ),
+
+            // Control frames carry nothing to store; they are printed by `pretty_print` at debug
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) => return Ok(()),
+            message => return Err(DecodeError::UnsupportedFrame(format!("{:?}", message)).into()),
+        };
+        self.send(Received {
+            value,
+            received_at,
+            frame: message,
+            frame_type,
+            origin,
+        })
+        .await
+    }
+
+    /// Hands a decoded message to the writer task.
+    async fn send(&self, received: Received) -> Result<(), MongoClientError> {
+        self.sender
+            .send(received)
+            .await
+            .map_err(|e| MongoClientError::from(Box::new(e)))
+    }
+
+    /// Stores a message that could not be processed in the dead-letter collection.
+    ///
+    /// The entry is built by `dead_letter_document`, with the current time as receive time.
+    /// It is written directly, bypassing the batching writer, so it also works when the writer
+    /// channel is closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The rejected message.
+    /// * `error` - The reason it was rejected.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MongoClientError::Write` if the entry cannot be inserted.
+    pub async fn dead_letter(
+        &self,
+        message: &Message,
+        error: &MongoClientError,
+    ) -> Result<(), MongoClientError> {
+        let document = dead_letter_document(message, error, &self.source, DateTime::now());
+        self.insert_dead_letter(document).await
+    }
+
+    /// Inserts an entry into the dead-letter collection.
+    async fn insert_dead_letter(&self, document: Document) -> Result<(), MongoClientError> {
+        self.dead_letter_collection
+            .insert_one(document, None)
+            .await
+            .map(|_| ())
+            .map_err(MongoClientError::Write)
+    }
 }
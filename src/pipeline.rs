@@ -0,0 +1,333 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+use crate::config::{Config, Secret, SecretUri};
+use crate::mongodb::{MongoClient, MongoClientError};
+use crate::subscription::SubscriptionHandle;
+use crate::websocket::{initial_messages, ConnectionStats, WebSocketClient};
+use mongodb::Client;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/// An enum representing the errors that can occur while starting pipelines.
+#[derive(Error, Debug)]
+pub enum PipelineError {
+    /// Error indicating that the MongoDB client of a pipeline could not be created.
+    #[error("pipeline {0}: {1}")]
+    Mongo(String, #[source] MongoClientError),
+
+    /// Error indicating that no pipeline has the requested name.
+    #[error("unknown pipeline: {0}")]
+    Unknown(String),
+
+    /// Error indicating that some pipelines failed, carrying their names.
+    #[error("{} pipeline(s) failed: {}", .0.len(), .0.join(", "))]
+    Failed(Vec<String>),
+}
+
+/// A named stream from one WebSocket source to one MongoDB collection.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    /// The name used in status reports.
+    pub name: String,
+
+    /// The settings of the pipeline.
+    pub config: Config,
+}
+
+impl Pipeline {
+    /// Creates a pipeline.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name used in status reports.
+    /// * `config` - The settings of the pipeline.
+    pub fn new(name: impl Into<String>, config: Config) -> Self {
+        Pipeline {
+            name: name.into(),
+            config,
+        }
+    }
+}
+
+/// The lifecycle state of a pipeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineState {
+    /// The WebSocket client is running, connected or reconnecting.
+    Running,
+
+    /// The WebSocket client returned without error.
+    Stopped,
+
+    /// The WebSocket client returned an error.
+    Failed(String),
+}
+
+impl fmt::Display for PipelineState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineState::Running => write!(f, "running"),
+            PipelineState::Stopped => write!(f, "stopped"),
+            PipelineState::Failed(error) => write!(f, "failed: {}", error),
+        }
+    }
+}
+
+/// A snapshot of the state and counters of a pipeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineStatus {
+    /// The name of the pipeline.
+    pub name: String,
+
+    /// The lifecycle state of the pipeline.
+    pub state: PipelineState,
+
+    /// Whether the pipeline currently holds an established WebSocket connection.
+    pub connected: bool,
+
+    /// Number of WebSocket connections established, including reconnections.
+    pub connections: u64,
+
+    /// Number of frames received.
+    pub messages_received: u64,
+
+    /// Number of connections dropped by the idle watchdog.
+    pub idle_timeouts: u64,
+}
+
+impl fmt::Display for PipelineStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}, {}, {} connections, {} messages, {} idle timeouts",
+            self.name,
+            self.state,
+            if self.connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            self.connections,
+            self.messages_received,
+            self.idle_timeouts
+        )
+    }
+}
+
+/// The settings a MongoDB client is built from: the URI, credentials and TLS settings read by
+/// `MongoClient::connect`. Pipelines with equal keys share one client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientKey {
+    uri: SecretUri,
+    user: Option<String>,
+    password: Option<Secret>,
+    auth_source: String,
+    auth_mechanism: String,
+    aws_session_token: Option<Secret>,
+    tls: Option<bool>,
+    tls_ca_file: Option<String>,
+    tls_cert_key_file: Option<String>,
+    tls_allow_invalid_hostnames: bool,
+}
+
+impl ClientKey {
+    /// Returns the key of the MongoDB client `config` connects with.
+    pub fn from_config(config: &Config) -> Self {
+        ClientKey {
+            uri: config.mongodb_uri.clone(),
+            user: config.mongodb_user.clone(),
+            password: config.mongodb_password.clone(),
+            auth_source: config.mongodb_auth_source.clone(),
+            auth_mechanism: config.mongodb_auth_mechanism.clone(),
+            aws_session_token: config.mongodb_aws_session_token.clone(),
+            tls: config.mongodb_tls,
+            tls_ca_file: config.mongodb_tls_ca_file.clone(),
+            tls_cert_key_file: config.mongodb_tls_cert_key_file.clone(),
+            tls_allow_invalid_hostnames: config.mongodb_tls_allow_invalid_hostnames,
+        }
+    }
+}
+
+/// A pipeline whose WebSocket client runs in its own task.
+struct RunningPipeline {
+    name: String,
+    stats: Arc<ConnectionStats>,
+    state: Arc<Mutex<PipelineState>>,
+    subscriptions: SubscriptionHandle,
+}
+
+/// Runs several pipelines in one process and reports their status.
+///
+/// Pipelines with the same MongoDB URI, credentials and TLS settings (see `ClientKey`) share one
+/// MongoDB client, and therefore one connection pool.
+pub struct PipelineSupervisor {
+    pipelines: Vec<RunningPipeline>,
+    finished: Arc<Notify>,
+}
+
+impl PipelineSupervisor {
+    /// Connects to MongoDB and starts every pipeline.
+    ///
+    /// # Arguments
+    ///
+    /// * `pipelines` - The pipelines to run.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PipelineError::Mongo` naming the first pipeline whose MongoDB connection
+    /// fails, or whose collection cannot be prepared (see `MongoClient::prepare`). No pipeline
+    /// is started in that case.
+    pub async fn start(pipelines: Vec<Pipeline>) -> Result<Self, PipelineError> {
+        let mut clients: HashMap<ClientKey, Client> = HashMap::new();
+        let mut connected = Vec::with_capacity(pipelines.len());
+        for pipeline in pipelines {
+            let config = &pipeline.config;
+            let key = ClientKey::from_config(config);
+            let client = match clients.get(&key) {
+                Some(client) => client.clone(),
+                None => {
+                    let client = MongoClient::connect(config)
+                        .await
+                        .map_err(|e| PipelineError::Mongo(pipeline.name.clone(), e))?;
+                    clients.insert(key, client.clone());
+                    client
+                }
+            };
+            MongoClient::prepare(&client, config)
+                .await
+                .map_err(|e| PipelineError::Mongo(pipeline.name.clone(), e))?;
+            connected.push((pipeline, client));
+        }
+
+        let mut supervisor = Self::empty();
+        for (pipeline, client) in connected {
+            supervisor.spawn(pipeline, &client);
+        }
+        Ok(supervisor)
+    }
+
+    /// Starts every pipeline on an existing MongoDB client, without checking the connection or
+    /// preparing the collections.
+    ///
+    /// # Arguments
+    ///
+    /// * `pipelines` - The pipelines to run.
+    /// * `client` - The MongoDB client shared by all pipelines.
+    pub fn start_with_client(pipelines: Vec<Pipeline>, client: &Client) -> Self {
+        let mut supervisor = Self::empty();
+        for pipeline in pipelines {
+            supervisor.spawn(pipeline, client);
+        }
+        supervisor
+    }
+
+    fn empty() -> Self {
+        PipelineSupervisor {
+            pipelines: Vec::new(),
+            finished: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Spawns the WebSocket client of a pipeline and records its outcome when it returns.
+    fn spawn(&mut self, pipeline: Pipeline, client: &Client) {
+        let Pipeline { name, config } = pipeline;
+        let mongo_client = MongoClient::with_client(client, &config);
+        let messages = initial_messages(&config);
+        let mut ws_client = WebSocketClient::new(config, None, messages, mongo_client);
+
+        let stats = ws_client.stats();
+        let subscriptions = ws_client.subscriptions();
+        let state = Arc::new(Mutex::new(PipelineState::Running));
+        let task_state = Arc::clone(&state);
+        let finished = Arc::clone(&self.finished);
+        let task_name = name.clone();
+        tokio::spawn(async move {
+            let outcome = match ws_client.run().await {
+                Ok(()) => PipelineState::Stopped,
+                Err(e) => {
+                    eprintln!("Pipeline {} stopped: {}", task_name, e);
+                    PipelineState::Failed(e.to_string())
+                }
+            };
+            *task_state.lock().unwrap_or_else(|e| e.into_inner()) = outcome;
+            finished.notify_waiters();
+        });
+
+        self.pipelines.push(RunningPipeline {
+            name,
+            stats,
+            state,
+            subscriptions,
+        });
+    }
+
+    /// Returns the status of every pipeline, in start order.
+    pub fn status(&self) -> Vec<PipelineStatus> {
+        self.pipelines
+            .iter()
+            .map(|pipeline| PipelineStatus {
+                name: pipeline.name.clone(),
+                state: pipeline
+                    .state
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .clone(),
+                connected: pipeline.stats.connected.load(Ordering::Relaxed),
+                connections: pipeline.stats.connections.load(Ordering::Relaxed),
+                messages_received: pipeline.stats.messages_received.load(Ordering::Relaxed),
+                idle_timeouts: pipeline.stats.idle_timeouts.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Returns the subscription handle of the pipeline called `name`, if there is one.
+    pub fn subscriptions(&self, name: &str) -> Option<SubscriptionHandle> {
+        self.pipelines
+            .iter()
+            .find(|pipeline| pipeline.name == name)
+            .map(|pipeline| pipeline.subscriptions.clone())
+    }
+
+    /// Returns `true` once every pipeline has stopped or failed.
+    pub fn is_finished(&self) -> bool {
+        self.status()
+            .iter()
+            .all(|status| status.state != PipelineState::Running)
+    }
+
+    /// Waits until every pipeline has stopped or failed.
+    pub async fn wait(&self) {
+        loop {
+            // Register before checking so a pipeline finishing in between is not missed
+            let notified = self.finished.notified();
+            if self.is_finished() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
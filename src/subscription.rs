@@ -0,0 +1,325 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+use crate::config::{expand_subscribe_template, Config};
+use crate::websocket::ConnectionStats;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// An enum representing the errors that can occur while changing subscriptions at runtime.
+#[derive(Error, Debug)]
+pub enum SubscriptionError {
+    /// Error indicating that no template is configured for the requested change.
+    #[error("missing subscription template: {0}")]
+    MissingTemplate(String),
+
+    /// Error indicating that the WebSocket client the handle belongs to has been dropped.
+    #[error("WebSocket client is no longer running")]
+    ClientGone,
+}
+
+/// The topics a WebSocket client is subscribed to, and the templates used to change them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubscriptionSet {
+    subscribe_template: Option<String>,
+    unsubscribe_template: Option<String>,
+    binary: bool,
+    topics: Vec<String>,
+}
+
+impl SubscriptionSet {
+    /// Creates the set from the subscription settings, starting with
+    /// `websocket_subscribe_symbols`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration holding the subscription templates and symbols.
+    pub fn from_config(config: &Config) -> Self {
+        let mut set = SubscriptionSet {
+            subscribe_template: config.websocket_subscribe_template.clone(),
+            unsubscribe_template: config.websocket_unsubscribe_template.clone(),
+            binary: config.websocket_subscribe_binary,
+            topics: Vec::new(),
+        };
+        set.insert(&config.websocket_subscribe_symbols);
+        set
+    }
+
+    /// Returns the subscribed topics, in subscription order.
+    pub fn topics(&self) -> &[String] {
+        &self.topics
+    }
+
+    /// Takes over the configured initial messages that subscribe to topics.
+    ///
+    /// A message is a subscription when the subscription template expands to it, e.g.
+    /// `sub:XRPUSD` for `sub:{symbol}`. Its topics join the set, so they are replayed, and can
+    /// be unsubscribed, like any other topic. The other messages are left to the caller.
+    ///
+    /// # Arguments
+    ///
+    /// * `messages` - The initial messages of the client.
+    ///
+    /// # Returns
+    ///
+    /// The messages that are not subscriptions, in their original order.
+    pub fn adopt(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        let Some(template) = self.subscribe_template.clone() else {
+            return messages;
+        };
+        let mut setup = Vec::with_capacity(messages.len());
+        for message in messages {
+            match self.message_topics(&template, &message) {
+                Some(topics) => {
+                    self.insert(&topics);
+                }
+                None => setup.push(message),
+            }
+        }
+        setup
+    }
+
+    /// Returns the topics `template` expands to `message`, if it does.
+    fn message_topics(&self, template: &str, message: &Message) -> Option<Vec<String>> {
+        let text = match message {
+            Message::Text(text) if !self.binary => text.as_str(),
+            Message::Binary(data) if self.binary => std::str::from_utf8(data).ok()?,
+            _ => return None,
+        };
+        let topics = if let Some((prefix, suffix)) = template.split_once("{symbols}") {
+            let symbols = text.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            serde_json::from_str::<Vec<String>>(symbols).ok()?
+        } else {
+            let (prefix, suffix) = template.split_once("{symbol}")?;
+            let symbol = text.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            // The symbol was pasted escaped, see `expand_subscribe_template`
+            vec![serde_json::from_str::<String>(&format!("\"{}\"", symbol)).ok()?]
+        };
+        // Templates using the placeholder several times are matched by expanding them back
+        let matches = !topics.is_empty()
+            && topics.iter().all(|topic| !topic.is_empty())
+            && expand_subscribe_template(template, &topics).concat() == text;
+        matches.then_some(topics)
+    }
+
+    /// Adds topics to the set.
+    ///
+    /// # Arguments
+    ///
+    /// * `topics` - The topics to add.
+    ///
+    /// # Returns
+    ///
+    /// The topics that were not already in the set.
+    pub fn insert(&mut self, topics: &[String]) -> Vec<String> {
+        let mut added = Vec::new();
+        for topic in topics {
+            if !self.topics.contains(topic) {
+                self.topics.push(topic.clone());
+                added.push(topic.clone());
+            }
+        }
+        added
+    }
+
+    /// Removes topics from the set.
+    ///
+    /// # Arguments
+    ///
+    /// * `topics` - The topics to remove.
+    ///
+    /// # Returns
+    ///
+    /// The topics that were in the set.
+    pub fn remove(&mut self, topics: &[String]) -> Vec<String> {
+        let mut removed = Vec::new();
+        for topic in topics {
+            if let Some(index) = self.topics.iter().position(|current| current == topic) {
+                removed.push(self.topics.remove(index));
+            }
+        }
+        removed
+    }
+
+    /// Builds the messages that subscribe to `topics`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SubscriptionError::MissingTemplate` if no subscription template is configured.
+    pub fn subscribe_messages(&self, topics: &[String]) -> Result<Vec<Message>, SubscriptionError> {
+        let template = self.subscribe_template.as_deref().ok_or_else(|| {
+            SubscriptionError::MissingTemplate("WEBSOCKET_SUBSCRIBE_TEMPLATE".to_string())
+        })?;
+        Ok(self.expand(template, topics))
+    }
+
+    /// Builds the messages that cancel the subscription to `topics`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SubscriptionError::MissingTemplate` if no unsubscription template is configured.
+    pub fn unsubscribe_messages(
+        &self,
+        topics: &[String],
+    ) -> Result<Vec<Message>, SubscriptionError> {
+        let template = self.unsubscribe_template.as_deref().ok_or_else(|| {
+            SubscriptionError::MissingTemplate("WEBSOCKET_UNSUBSCRIBE_TEMPLATE".to_string())
+        })?;
+        Ok(self.expand(template, topics))
+    }
+
+    /// Builds the messages that restore the whole set on a new connection.
+    ///
+    /// Returns no message when the set is empty or no subscription template is configured.
+    pub fn replay_messages(&self) -> Vec<Message> {
+        self.subscribe_messages(&self.topics).unwrap_or_default()
+    }
+
+    fn expand(&self, template: &str, topics: &[String]) -> Vec<Message> {
+        if topics.is_empty() {
+            return Vec::new();
+        }
+        expand_subscribe_template(template, topics)
+            .into_iter()
+            .map(|message| {
+                if self.binary {
+                    Message::Binary(message.into_bytes())
+                } else {
+                    Message::Text(message)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Changes the subscriptions of a running `WebSocketClient`.
+///
+/// Changes made while the client is disconnected only update the set, which is replayed once
+/// the client reconnects.
+#[derive(Debug, Clone)]
+pub struct SubscriptionHandle {
+    set: Arc<Mutex<SubscriptionSet>>,
+    outbound: Sender<Message>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl SubscriptionHandle {
+    pub(crate) fn new(
+        set: Arc<Mutex<SubscriptionSet>>,
+        outbound: Sender<Message>,
+        stats: Arc<ConnectionStats>,
+    ) -> Self {
+        SubscriptionHandle {
+            set,
+            outbound,
+            stats,
+        }
+    }
+
+    /// Returns the subscribed topics, in subscription order.
+    pub async fn topics(&self) -> Vec<String> {
+        self.set.lock().await.topics().to_vec()
+    }
+
+    /// Subscribes to topics that are not already in the set.
+    ///
+    /// # Arguments
+    ///
+    /// * `topics` - The topics to subscribe to.
+    ///
+    /// # Returns
+    ///
+    /// The topics that were added.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SubscriptionError::MissingTemplate` if no subscription template is configured,
+    /// and `SubscriptionError::ClientGone` if the client has been dropped.
+    pub async fn subscribe(&self, topics: &[String]) -> Result<Vec<String>, SubscriptionError> {
+        let (added, messages) = {
+            let mut set = self.set.lock().await;
+            set.subscribe_messages(topics)?;
+            let added = set.insert(topics);
+            let messages = self.pending(set.subscribe_messages(&added)?);
+            (added, messages)
+        };
+        self.send(messages).await?;
+        Ok(added)
+    }
+
+    /// Unsubscribes from topics that are in the set.
+    ///
+    /// # Arguments
+    ///
+    /// * `topics` - The topics to unsubscribe from.
+    ///
+    /// # Returns
+    ///
+    /// The topics that were removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SubscriptionError::MissingTemplate` if no unsubscription template is configured,
+    /// and `SubscriptionError::ClientGone` if the client has been dropped.
+    pub async fn unsubscribe(&self, topics: &[String]) -> Result<Vec<String>, SubscriptionError> {
+        let (removed, messages) = {
+            let mut set = self.set.lock().await;
+            set.unsubscribe_messages(topics)?;
+            let removed = set.remove(topics);
+            let messages = self.pending(set.unsubscribe_messages(&removed)?);
+            (removed, messages)
+        };
+        self.send(messages).await?;
+        Ok(removed)
+    }
+
+    /// Keeps the messages of a change only if the client is connected; otherwise the next
+    /// connection replays the updated set.
+    ///
+    /// Must be called with the set locked, so a connection cannot replay the set in between.
+    fn pending(&self, messages: Vec<Message>) -> Vec<Message> {
+        if self.stats.connected.load(Ordering::Relaxed) {
+            messages
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Queues messages on the outbound channel of the client.
+    ///
+    /// Must be called with the set unlocked: the channel is bounded, and a connection being
+    /// (re)established needs the set before its writer drains the channel.
+    async fn send(&self, messages: Vec<Message>) -> Result<(), SubscriptionError> {
+        for message in messages {
+            self.outbound
+                .send(message)
+                .await
+                .map_err(|_| SubscriptionError::ClientGone)?;
+        }
+        Ok(())
+    }
+}
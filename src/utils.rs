@@ -22,18 +22,101 @@
 ******************************************************************************/
 
 use serde_json::Value;
-use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use thiserror::Error;
 use tokio_tungstenite::tungstenite::protocol::Message;
 
-pub fn pretty_print(message: Message) -> Result<(), Box<dyn Error>> {
+/// An enum representing the errors that can occur while decoding a WebSocket message.
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    /// Error indicating that a text or binary frame does not hold valid JSON.
+    #[error("invalid JSON payload: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// Error indicating that the frame type cannot carry a payload to store.
+    #[error("unsupported WebSocket frame: {0}")]
+    UnsupportedFrame(String),
+}
+
+/// How much the bridge writes to stdout and stderr, from least to most verbose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LogLevel {
+    /// Only errors that lose data or stop a client.
+    Error = 0,
+
+    /// Also recoverable problems, such as reconnections and idle timeouts.
+    Warn = 1,
+
+    /// Also lifecycle events, such as connections and pipeline status.
+    #[default]
+    Info = 2,
+
+    /// Also every received message.
+    Debug = 3,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            other => Err(format!("unknown log level: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogLevel::Error => write!(f, "error"),
+            LogLevel::Warn => write!(f, "warn"),
+            LogLevel::Info => write!(f, "info"),
+            LogLevel::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Sets the process-wide log level.
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns the process-wide log level.
+pub fn log_level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        2 => LogLevel::Info,
+        _ => LogLevel::Debug,
+    }
+}
+
+/// Returns `true` if messages of the given level should be written.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level <= log_level()
+}
+
+/// Prints a WebSocket message, pretty-printing JSON payloads.
+///
+/// # Errors
+///
+/// Returns a `DecodeError::Json` if a text or binary frame is not valid JSON.
+pub fn pretty_print(message: &Message) -> Result<(), DecodeError> {
     match message {
         Message::Text(text) => {
-            let parsed_json: Value = serde_json::from_str(&text)?;
+            let parsed_json: Value = serde_json::from_str(text)?;
             println!("Text: {}", serde_json::to_string_pretty(&parsed_json)?);
         }
         Message::Binary(data) => {
-            let parsed_json: Value = serde_json::from_slice(&data)?;
+            let parsed_json: Value = serde_json::from_slice(data)?;
             println!("Binary: {}", serde_json::to_string_pretty(&parsed_json)?);
         }
         Message::Ping(ping_data) => {
@@ -55,14 +138,3 @@ pub fn pretty_print(message: Message) -> Result<(), Box<dyn Error>> {
     }
     Ok(())
 }
-
-#[derive(Debug)]
-pub struct CustomError(pub String);
-
-impl fmt::Display for CustomError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-
-impl Error for CustomError {}
@@ -21,13 +21,28 @@
    Date: 11/5/24
 ******************************************************************************/
 
-use crate::config::Config;
-use crate::mongodb::MongoClient;
-use crate::utils::pretty_print;
+use crate::auth::{build_auth_message, classify_auth_response, generate_nonce, AuthResponse};
+use crate::config::{
+    AuthMessageConfig, Config, ConfigError, ErrorPolicy, InitialMessage, Secret, WebSocketAuthMode,
+};
+use crate::mongodb::{MessageOrigin, MongoClient, MongoClientError};
+use crate::subscription::{SubscriptionHandle, SubscriptionSet};
+use crate::utils::{log_enabled, pretty_print, DecodeError, LogLevel};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt}; // To access send and next methods
-use std::error::Error;
+use mongodb::bson::oid::ObjectId;
+use rand::Rng;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Mutex;
+use tokio::time::{Instant, Interval};
 use tokio_tungstenite::{
     connect_async, connect_async_tls_with_config, tungstenite::protocol::Message, Connector,
     MaybeTlsStream, WebSocketStream,
@@ -35,11 +50,359 @@ use tokio_tungstenite::{
 use tungstenite::client::IntoClientRequest;
 use url::Url;
 
+/// An enum representing the errors that make `WebSocketClient::run` stop.
+#[derive(Error, Debug)]
+pub enum WebSocketError {
+    /// Error indicating that the reconnection budget was used up.
+    #[error("giving up after {0} failed reconnection attempts: {1}")]
+    ReconnectAttemptsExhausted(u32, String),
+
+    /// Error indicating that the server rejected the authentication message.
+    #[error("authentication failed: {0}")]
+    Authentication(String),
+
+    /// Error indicating that the server did not confirm the authentication message in time,
+    /// carrying the timeout in milliseconds. Unlike a rejection, it is retried.
+    #[error("no authentication response within {0} ms")]
+    AuthenticationTimeout(u64),
+
+    /// Error indicating that the TCP or TLS connection could not be established.
+    #[error("failed to connect: {0}")]
+    Connect(#[source] tungstenite::Error),
+
+    /// Error indicating that the WebSocket upgrade was rejected or malformed.
+    #[error("WebSocket handshake failed: {0}")]
+    Handshake(#[source] tungstenite::Error),
+
+    /// Error indicating that the handshake request could not be built (e.g. an invalid header).
+    #[error("invalid handshake request: {0}")]
+    Request(#[from] http::Error),
+
+    /// Error indicating that the TLS connector could not be created.
+    #[error("TLS setup failed: {0}")]
+    Tls(#[from] native_tls::Error),
+
+    /// Error indicating that a message could not be written to the socket.
+    #[error("failed to send message: {0}")]
+    Send(#[source] tungstenite::Error),
+
+    /// Error indicating that a message could not be read from the socket.
+    #[error("failed to receive message: {0}")]
+    Receive(#[source] tungstenite::Error),
+
+    /// Error indicating that no connection is established.
+    #[error("WebSocket connection not established")]
+    NotConnected,
+
+    /// Error indicating that the server closed the connection.
+    #[error("WebSocket connection closed")]
+    Closed,
+
+    /// Error indicating that a received message could not be handed to MongoDB.
+    #[error(transparent)]
+    Mongo(#[from] MongoClientError),
+
+    /// Error indicating that the configuration cannot work (e.g. a malformed URL).
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+}
+
+impl WebSocketError {
+    /// Classifies an error returned while opening a connection.
+    ///
+    /// Transport failures (I/O, TLS, URL) become `Connect`, anything that happened once the
+    /// server answered becomes `Handshake`.
+    fn from_connect_error(error: tungstenite::Error) -> Self {
+        match error {
+            tungstenite::Error::Io(_) | tungstenite::Error::Tls(_) | tungstenite::Error::Url(_) => {
+                WebSocketError::Connect(error)
+            }
+            _ => WebSocketError::Handshake(error),
+        }
+    }
+}
+
+/// Describes how `WebSocketClient::run` waits between reconnection attempts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay ceiling for the first attempt.
+    pub initial_delay: Duration,
+
+    /// Upper bound for the delay ceiling.
+    pub max_delay: Duration,
+
+    /// Factor applied to the delay ceiling after every failed attempt.
+    pub multiplier: f64,
+
+    /// Number of consecutive failed attempts after which the client gives up, if any.
+    pub max_attempts: Option<u32>,
+
+    /// Time a connection must stay up for the attempt counter to be reset.
+    pub reset_after: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Builds the reconnection policy from the `WEBSOCKET_RECONNECT_*` configuration options.
+    pub fn from_config(config: &Config) -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(config.websocket_reconnect_initial_delay_ms),
+            max_delay: Duration::from_millis(config.websocket_reconnect_max_delay_ms),
+            multiplier: config.websocket_reconnect_multiplier,
+            max_attempts: config.websocket_reconnect_max_attempts,
+            reset_after: Duration::from_millis(config.websocket_reconnect_reset_after_ms),
+        }
+    }
+
+    /// Returns the delay ceiling for the given attempt (starting at 1), before jitter is applied.
+    ///
+    /// The ceiling grows as `initial_delay * multiplier^(attempt - 1)` and is capped at `max_delay`.
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let ceiling = self.initial_delay.as_secs_f64() * self.multiplier.max(1.0).powi(exponent);
+        if ceiling.is_finite() && ceiling < self.max_delay.as_secs_f64() {
+            Duration::from_secs_f64(ceiling)
+        } else {
+            self.max_delay
+        }
+    }
+}
+
+/// Tracks consecutive failed connection attempts and computes the delay before the next one.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempts: u32,
+}
+
+impl Backoff {
+    /// Creates a new `Backoff` with no failed attempts recorded.
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Backoff {
+            policy,
+            attempts: 0,
+        }
+    }
+
+    /// Returns the number of consecutive failed attempts recorded so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next one.
+    ///
+    /// The delay is drawn uniformly between zero and the policy ceiling ("full jitter").
+    ///
+    /// # Returns
+    ///
+    /// `None` if the policy's `max_attempts` has been reached and no retry should be made.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts = self.attempts.saturating_add(1);
+        if let Some(max_attempts) = self.policy.max_attempts {
+            if self.attempts >= max_attempts {
+                return None;
+            }
+        }
+
+        let ceiling = self.policy.ceiling(self.attempts);
+        Some(ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0)))
+    }
+
+    /// Clears the failed attempt counter after a connection has been stable long enough.
+    ///
+    /// # Arguments
+    ///
+    /// * `uptime` - How long the connection stayed up.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the connection was stable, `false` if it should count as a failed attempt.
+    pub fn connection_ended(&mut self, uptime: Duration) -> bool {
+        let stable = uptime >= self.policy.reset_after;
+        if stable {
+            self.attempts = 0;
+        }
+        stable
+    }
+}
+
+/// Builds the headers added to the WebSocket handshake request.
+///
+/// The `Authorization` header derived from `websocket_auth_mode` comes first, followed by the
+/// custom `websocket_headers`.
+///
+/// # Arguments
+///
+/// * `config` - The configuration holding the credentials and custom headers.
+pub fn handshake_headers(config: &Config) -> Vec<(String, String)> {
+    let mut headers = Vec::with_capacity(config.websocket_headers.len() + 1);
+    let api_key = config.websocket_api_key.as_deref().unwrap_or_default();
+    let api_secret = config
+        .websocket_api_secret
+        .as_ref()
+        .map(Secret::expose)
+        .unwrap_or_default();
+
+    match config.websocket_auth_mode {
+        WebSocketAuthMode::None => {}
+        WebSocketAuthMode::Bearer => {
+            headers.push(("Authorization".to_string(), format!("Bearer {}", api_key)));
+        }
+        WebSocketAuthMode::Basic => {
+            let credentials = BASE64.encode(format!("{}:{}", api_key, api_secret));
+            headers.push((
+                "Authorization".to_string(),
+                format!("Basic {}", credentials),
+            ));
+        }
+    }
+
+    headers.extend(
+        config
+            .websocket_headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.expose().to_string())),
+    );
+    headers
+}
+
+/// An application-level heartbeat sent periodically by the writer task.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heartbeat {
+    /// Time between two heartbeats.
+    pub interval: Duration,
+
+    /// The message sent on every tick.
+    pub message: Message,
+}
+
+impl Heartbeat {
+    /// Builds the heartbeat from the `WEBSOCKET_HEARTBEAT_*` configuration options.
+    ///
+    /// # Returns
+    ///
+    /// `None` if no heartbeat interval is configured.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let interval_ms = config
+            .websocket_heartbeat_interval_ms
+            .filter(|ms| *ms > 0)?;
+        let message = match &config.websocket_heartbeat_message {
+            Some(text) => Message::Text(text.clone()),
+            None => Message::Ping(Vec::new()),
+        };
+        Some(Heartbeat {
+            interval: Duration::from_millis(interval_ms),
+            message,
+        })
+    }
+}
+
+/// Detects stale connections that stay open without delivering data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdleWatchdog {
+    /// Time without activity after which the connection is dropped.
+    pub timeout: Duration,
+
+    /// If `true`, control frames (ping, pong, close) do not count as activity.
+    pub data_only: bool,
+
+    /// If set, only JSON data frames containing this pointer count as activity.
+    pub data_pointer: Option<String>,
+}
+
+impl IdleWatchdog {
+    /// Builds the watchdog from the `WEBSOCKET_IDLE_*` configuration options.
+    ///
+    /// # Returns
+    ///
+    /// `None` if no idle timeout is configured.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let timeout_ms = config.websocket_idle_timeout_ms.filter(|ms| *ms > 0)?;
+        Some(IdleWatchdog {
+            timeout: Duration::from_millis(timeout_ms),
+            data_only: config.websocket_idle_data_only,
+            data_pointer: config.websocket_idle_data_pointer.clone(),
+        })
+    }
+
+    /// Returns `true` if receiving `message` proves the connection is alive.
+    pub fn is_activity(&self, message: &Message) -> bool {
+        if let Some(pointer) = &self.data_pointer {
+            let json = match message {
+                Message::Text(text) => serde_json::from_str::<Value>(text).ok(),
+                Message::Binary(data) => serde_json::from_slice::<Value>(data).ok(),
+                _ => None,
+            };
+            return json.is_some_and(|json| json.pointer(pointer).is_some());
+        }
+        if self.data_only {
+            return matches!(message, Message::Text(_) | Message::Binary(_));
+        }
+        true
+    }
+}
+
+/// Counters describing the activity of a `WebSocketClient`, readable while `run` is active.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    /// Whether the client currently holds an established connection.
+    pub connected: AtomicBool,
+
+    /// Number of connections established, including reconnections.
+    pub connections: AtomicU64,
+
+    /// Number of frames received over all connections.
+    pub messages_received: AtomicU64,
+
+    /// Number of connections dropped by the idle watchdog.
+    pub idle_timeouts: AtomicU64,
+}
+
+/// Returns the configured initial messages as WebSocket frames.
+///
+/// # Arguments
+///
+/// * `config` - The configuration holding `websocket_initial_messages`.
+pub fn initial_messages(config: &Config) -> Vec<Message> {
+    config
+        .websocket_initial_messages
+        .iter()
+        .map(|message| match message {
+            InitialMessage::Text(text) => Message::Text(text.clone()),
+            InitialMessage::Binary(data) => Message::Binary(data.clone()),
+        })
+        .collect()
+}
+
+/// Completes on the next tick of `interval`, or never if there is no interval.
+async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+type WebSocketSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
 pub struct WebSocketClient {
     pub config: Config,
     pub socket: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
     initial_messages: Vec<Message>, // Store initial messages to be sent upon connection
     pub mongo_client: Arc<MongoClient>,
+
+    /// The sender part of the channel for messages to write on the current connection.
+    outbound_sender: Sender<Message>,
+
+    /// The receiver part of the outbound channel, owned by the writer task of the current connection.
+    outbound_receiver: Arc<Mutex<Receiver<Message>>>,
+
+    /// Counters shared with whoever monitors the client.
+    stats: Arc<ConnectionStats>,
+
+    /// The current subscriptions, replayed after the other initial messages on every
+    /// (re)connection.
+    subscriptions: Arc<Mutex<SubscriptionSet>>,
 }
 
 impl WebSocketClient {
@@ -49,16 +412,54 @@ impl WebSocketClient {
         initial_messages: Vec<Message>,
         mongo_client: Arc<MongoClient>,
     ) -> Self {
+        let (outbound_sender, outbound_receiver) = mpsc::channel(100); // Buffer size of 100
+                                                                       // Subscriptions among the initial messages are managed, and replayed, by the set
+        let mut subscriptions = SubscriptionSet::from_config(&config);
+        let initial_messages = subscriptions.adopt(initial_messages);
         WebSocketClient {
             config,
             socket,
             initial_messages, // Initialize with the provided messages
             mongo_client,
+            outbound_sender,
+            outbound_receiver: Arc::new(Mutex::new(outbound_receiver)),
+            stats: Arc::new(ConnectionStats::default()),
+            subscriptions: Arc::new(Mutex::new(subscriptions)),
         }
     }
 
-    pub async fn connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let url = Url::parse(&self.config.websocket_url).unwrap();
+    /// Returns the counters of this client, which keep updating while `run` is active.
+    pub fn stats(&self) -> Arc<ConnectionStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Returns a sender for messages to write on the connection managed by `run`.
+    ///
+    /// Messages sent while disconnected are buffered and written once the client reconnects.
+    pub fn outbound_sender(&self) -> Sender<Message> {
+        self.outbound_sender.clone()
+    }
+
+    /// Returns a handle to subscribe and unsubscribe topics while `run` is active.
+    ///
+    /// The client remembers the resulting subscription set and replays it after every reconnect.
+    /// Initial messages that subscribe to topics belong to the set (see `SubscriptionSet::adopt`),
+    /// so a topic unsubscribed through the handle stays unsubscribed after a reconnect.
+    pub fn subscriptions(&self) -> SubscriptionHandle {
+        SubscriptionHandle::new(
+            Arc::clone(&self.subscriptions),
+            self.outbound_sender.clone(),
+            Arc::clone(&self.stats),
+        )
+    }
+
+    pub async fn connect(&mut self) -> Result<(), WebSocketError> {
+        let invalid_url =
+            |reason: String| ConfigError::InvalidUrl(self.config.websocket_url.clone(), reason);
+        let url = Url::parse(&self.config.websocket_url).map_err(|e| invalid_url(e.to_string()))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid_url("missing host".to_string()))?;
 
         let mut request_builder = http::Request::builder()
             .uri(url.as_str())
@@ -67,108 +468,364 @@ impl WebSocketClient {
                 tokio_tungstenite::tungstenite::handshake::client::generate_key(),
             )
             .header("Sec-WebSocket-Version", "13")
-            .header("host", url.host_str().unwrap())
+            .header("host", host)
             .header("Connection", "Upgrade")
             .header("Upgrade", "websocket");
 
-        // Only add the headers if they are set
-        if let Some(api_key) = &self.config.websocket_api_key {
-            request_builder = request_builder.header("APCA-API-KEY-ID", api_key);
-        }
-
-        if let Some(api_secret) = &self.config.websocket_api_secret {
-            request_builder = request_builder.header("APCA-API-SECRET-KEY", api_secret);
+        for (name, value) in handshake_headers(&self.config) {
+            request_builder = request_builder.header(name, value);
         }
 
         let request = request_builder
-            .body(())
-            .map_err(|e| Box::new(e) as Box<dyn Error>)?
-            .into_client_request()?;
+            .body(())?
+            .into_client_request()
+            .map_err(WebSocketError::Handshake)?;
 
         if url.scheme() == "wss" {
-            let tls_connector = native_tls::TlsConnector::builder().build().unwrap();
+            let tls_connector = native_tls::TlsConnector::builder().build()?;
             let connector = Connector::NativeTls(tls_connector);
             let (ws_stream, _) =
-                connect_async_tls_with_config(request, None, false, Some(connector)).await?;
+                connect_async_tls_with_config(request, None, false, Some(connector))
+                    .await
+                    .map_err(WebSocketError::from_connect_error)?;
             self.socket = Some(ws_stream);
         } else {
-            let (ws_stream, _) = connect_async(request).await?;
+            let (ws_stream, _) = connect_async(request)
+                .await
+                .map_err(WebSocketError::from_connect_error)?;
             self.socket = Some(ws_stream);
         }
 
-        // Send initial messages if the connection is successful
+        if let Some(auth) = self.config.websocket_auth_message.clone() {
+            self.authenticate(&auth).await?;
+        }
+
+        // Send initial messages and the current subscriptions if the connection is successful,
+        // spaced to respect rate limits. The set stays locked until the connection is marked as
+        // up, so runtime changes are either replayed here or sent on the connection afterwards.
+        let subscriptions = Arc::clone(&self.subscriptions);
+        let subscriptions = subscriptions.lock().await;
+        let delay = self
+            .config
+            .websocket_initial_messages_delay_ms
+            .map(Duration::from_millis);
         if let Some(ref mut socket) = self.socket {
-            for message in &self.initial_messages {
-                socket
-                    .send(message.clone())
-                    .await
-                    .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)?;
+            let messages = self
+                .initial_messages
+                .iter()
+                .cloned()
+                .chain(subscriptions.replay_messages());
+            for (index, message) in messages.enumerate() {
+                if let Some(delay) = delay.filter(|_| index > 0) {
+                    tokio::time::sleep(delay).await;
+                }
+                socket.send(message).await.map_err(WebSocketError::Send)?;
             }
         }
 
+        self.stats.connections.fetch_add(1, Ordering::Relaxed);
+        self.stats.connected.store(true, Ordering::Relaxed);
         Ok(())
     }
 
+    /// Sends the authentication message and waits for the server to confirm it.
+    ///
+    /// Messages that do not match the success or failure rules are logged and discarded.
+    ///
+    /// # Errors
+    ///
+    /// Returns `WebSocketError::Authentication` if the server rejects the message,
+    /// `WebSocketError::AuthenticationTimeout` if it does not answer within the configured
+    /// timeout, `WebSocketError::Closed` if it closes the connection first, and
+    /// `WebSocketError::Send` or `WebSocketError::Receive` on transport failures.
+    async fn authenticate(&mut self, auth: &AuthMessageConfig) -> Result<(), WebSocketError> {
+        let socket = self.socket.as_mut().ok_or(WebSocketError::NotConnected)?;
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let message = build_auth_message(
+            auth,
+            self.config.websocket_api_key.as_deref().unwrap_or_default(),
+            self.config
+                .websocket_api_secret
+                .as_ref()
+                .map(Secret::expose)
+                .unwrap_or_default(),
+            timestamp_ms,
+            &generate_nonce(),
+        );
+        socket
+            .send(Message::Text(message))
+            .await
+            .map_err(WebSocketError::Send)?;
+
+        if auth.success_pointer.is_none() {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(auth.timeout_ms);
+        loop {
+            let message = match tokio::time::timeout_at(deadline, socket.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(e))) => return Err(WebSocketError::Receive(e)),
+                Ok(None) => return Err(WebSocketError::Closed),
+                Err(_) => return Err(WebSocketError::AuthenticationTimeout(auth.timeout_ms)),
+            };
+
+            match classify_auth_response(auth, &message) {
+                AuthResponse::Accepted => return Ok(()),
+                AuthResponse::Rejected => {
+                    return Err(WebSocketError::Authentication(format!(
+                        "rejected by server: {}",
+                        message
+                    )))
+                }
+                AuthResponse::Unrelated => {
+                    if log_enabled(LogLevel::Debug) {
+                        println!(
+                            "Ignoring message received during authentication: {}",
+                            message
+                        )
+                    }
+                }
+            }
+        }
+    }
+
     // Asynchronously sends a message using the WebSocket
-    pub async fn send_message(&mut self, message: Message) -> Result<(), Box<dyn Error>> {
+    pub async fn send_message(&mut self, message: Message) -> Result<(), WebSocketError> {
         if let Some(socket) = &mut self.socket {
-            socket
-                .send(message)
-                .await
-                .map_err(|e| Box::new(e) as Box<dyn Error>)
+            socket.send(message).await.map_err(WebSocketError::Send)
         } else {
-            Err("WebSocket connection not established".into())
+            Err(WebSocketError::NotConnected)
         }
     }
 
     // Asynchronously receives a message from the WebSocket
-    pub async fn receive_message(&mut self) -> Result<Message, Box<dyn Error>> {
+    pub async fn receive_message(&mut self) -> Result<Message, WebSocketError> {
         if let Some(socket) = &mut self.socket {
             match socket.next().await {
                 Some(Ok(msg)) => Ok(msg),
-                Some(Err(e)) => Err(Box::new(e) as Box<dyn Error>),
-                None => Err("No message received".into()),
+                Some(Err(e)) => Err(WebSocketError::Receive(e)),
+                None => Err(WebSocketError::Closed),
             }
         } else {
-            Err("WebSocket connection not established".into())
+            Err(WebSocketError::NotConnected)
         }
     }
 
-    // Manages the WebSocket connection
-    pub async fn run(&mut self) {
+    /// Manages the WebSocket connection, reconnecting according to the configured `ReconnectPolicy`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `WebSocketError::ReconnectAttemptsExhausted` once `websocket_reconnect_max_attempts`
+    /// consecutive connection attempts have failed. Without a limit it never returns.
+    pub async fn run(&mut self) -> Result<(), WebSocketError> {
+        let mut backoff = Backoff::new(ReconnectPolicy::from_config(&self.config));
+
         loop {
             let maybe_socket = self.socket.take(); // Temporarily take the socket
 
             if let Some(socket) = maybe_socket {
-                let (_write, mut read) = socket.split();
-                while let Some(msg) = read.next().await {
+                let connected_at = Instant::now();
+                let (write, mut read) = socket.split();
+                // Pings are answered by tungstenite itself, on the next read
+                let mut writer = tokio::spawn(Self::write_loop(
+                    write,
+                    Arc::clone(&self.outbound_receiver),
+                    Heartbeat::from_config(&self.config),
+                ));
+
+                let connection_id = ObjectId::new();
+                let mut seq = 0;
+                let watchdog = IdleWatchdog::from_config(&self.config);
+                let mut idle_deadline = watchdog
+                    .as_ref()
+                    .map(|watchdog| Instant::now() + watchdog.timeout);
+
+                loop {
+                    let next = tokio::select! {
+                        next = async {
+                            match idle_deadline {
+                                Some(deadline) => {
+                                    tokio::time::timeout_at(deadline, read.next()).await.ok()
+                                }
+                                None => Some(read.next().await),
+                            }
+                        } => next,
+                        _ = &mut writer => {
+                            if log_enabled(LogLevel::Warn) {
+                                eprintln!(
+                                    "Writing to {} failed, reconnecting",
+                                    self.config.websocket_url
+                                );
+                            }
+                            break; // The connection is half dead, drop it and reconnect
+                        }
+                    };
+                    let Some(next) = next else {
+                        self.stats.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+                        if log_enabled(LogLevel::Warn) {
+                            eprintln!(
+                                "No activity on {} within the idle timeout, reconnecting",
+                                self.config.websocket_url
+                            );
+                        }
+                        break; // Drop the stale connection and reconnect
+                    };
+                    let Some(msg) = next else {
+                        break;
+                    };
+
                     match msg {
                         Ok(message) => {
-                            if let Err(e) = self.send_to_mongo(message.clone()).await {
-                                eprintln!("Error processing message: {}", e);
-                            } else {
-                                pretty_print(message.clone()).unwrap();
+                            self.stats.messages_received.fetch_add(1, Ordering::Relaxed);
+                            if let Some(watchdog) = &watchdog {
+                                if watchdog.is_activity(&message) {
+                                    idle_deadline = Some(Instant::now() + watchdog.timeout);
+                                }
+                            }
+                            seq += 1;
+                            let origin = MessageOrigin { connection_id, seq };
+                            if let Err(e) = self.handle_message(message, origin).await {
+                                writer.abort();
+                                self.stats.connected.store(false, Ordering::Relaxed);
+                                return Err(e);
                             }
                         }
                         Err(e) => {
-                            eprintln!("Error in receiving message: {}", e);
+                            if log_enabled(LogLevel::Warn) {
+                                eprintln!("Error in receiving message: {}", e);
+                            }
                             break; // Exit the inner loop to attempt reconnection
                         }
                     }
                 }
+                writer.abort();
+                self.stats.connected.store(false, Ordering::Relaxed);
+                // A connection that drops quickly counts as a failed attempt to avoid a tight loop
+                if !backoff.connection_ended(connected_at.elapsed()) {
+                    Self::wait_before_retry(&mut backoff, "connection closed early").await?;
+                }
             }
 
             // Attempt to reconnect
-            if let Err(e) = self.connect().await {
-                eprintln!("Failed to reconnect: {}", e);
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await; // Delay before retrying
+            match self.connect().await {
+                Ok(()) => {}
+                Err(e @ (WebSocketError::Authentication(_) | WebSocketError::Config(_))) => {
+                    // Retrying with the same credentials or configuration would fail again
+                    self.socket = None;
+                    return Err(e);
+                }
+                Err(e) => {
+                    // Drop a connection that failed after opening, e.g. during authentication
+                    self.socket = None;
+                    if log_enabled(LogLevel::Warn) {
+                        eprintln!("Failed to reconnect: {}", e);
+                    }
+                    Self::wait_before_retry(&mut backoff, &e.to_string()).await?;
+                }
+            }
+        }
+    }
+
+    /// Owns the write half of a connection for as long as it is up.
+    ///
+    /// Writes messages from the outbound channel and the periodic heartbeat. Stops when a write
+    /// fails, which makes `run` drop the connection and reconnect.
+    async fn write_loop(
+        mut write: WebSocketSink,
+        outbound: Arc<Mutex<Receiver<Message>>>,
+        heartbeat: Option<Heartbeat>,
+    ) {
+        let mut outbound = outbound.lock().await;
+        let mut interval = heartbeat.as_ref().map(|heartbeat| {
+            tokio::time::interval_at(Instant::now() + heartbeat.interval, heartbeat.interval)
+        });
+
+        loop {
+            let message = tokio::select! {
+                biased;
+                Some(message) = outbound.recv() => message,
+                _ = next_tick(&mut interval) => match &heartbeat {
+                    Some(heartbeat) => heartbeat.message.clone(),
+                    None => continue,
+                },
+                else => break,
+            };
+
+            if let Err(e) = write.send(message).await {
+                eprintln!("Error writing to WebSocket: {}", e);
+                break;
+            }
+        }
+    }
+
+    /// Sleeps for the next backoff delay, or fails if the reconnection budget is used up.
+    async fn wait_before_retry(backoff: &mut Backoff, reason: &str) -> Result<(), WebSocketError> {
+        match backoff.next_delay() {
+            Some(delay) => {
+                tokio::time::sleep(delay).await;
+                Ok(())
             }
+            None => Err(WebSocketError::ReconnectAttemptsExhausted(
+                backoff.attempts(),
+                reason.to_string(),
+            )),
         }
     }
 
-    // Separar la lógica que involucra el lock en una función dedicada
-    async fn send_to_mongo(&self, message: Message) -> Result<(), Box<dyn Error>> {
-        let enqueuing_result = self.mongo_client.enqueue(message).await.unwrap();
-        Ok(enqueuing_result)
+    /// Hands a received message to MongoDB, applying the configured `ErrorPolicy` on failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns the failure only if its policy is `ErrorPolicy::Abort`.
+    async fn handle_message(
+        &self,
+        message: Message,
+        origin: MessageOrigin,
+    ) -> Result<(), WebSocketError> {
+        // Shared with the writer task, which keeps the frame in case it has to dead-letter it
+        let message = Arc::new(message);
+        let error = match self
+            .mongo_client
+            .enqueue_from(Arc::clone(&message), Some(origin))
+            .await
+        {
+            Ok(()) => {
+                if log_enabled(LogLevel::Debug) {
+                    if let Err(e) = pretty_print(&message) {
+                        eprintln!("Error printing message: {}", e);
+                    }
+                }
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        match self.error_policy(&error) {
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Log => eprintln!("Error processing message: {}", error),
+            ErrorPolicy::DeadLetter => {
+                if let Err(e) = self.mongo_client.dead_letter(&message, &error).await {
+                    eprintln!("Error storing dead letter ({}): {}", error, e);
+                }
+            }
+            ErrorPolicy::Abort => return Err(error.into()),
+        }
+        Ok(())
+    }
+
+    /// Returns the policy configured for the kind of `error`.
+    fn error_policy(&self, error: &MongoClientError) -> ErrorPolicy {
+        match error {
+            MongoClientError::Decode(DecodeError::Json(_)) => self.config.decode_error_policy,
+            MongoClientError::Decode(DecodeError::UnsupportedFrame(_)) => {
+                self.config.unsupported_frame_policy
+            }
+            _ => self.config.enqueue_error_policy,
+        }
     }
 }
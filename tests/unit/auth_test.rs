@@ -0,0 +1,110 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+#[cfg(test)]
+mod auth_tests {
+    use tokio_tungstenite::tungstenite::Message;
+    use ws2mongo::auth::{build_auth_message, classify_auth_response, sign, AuthResponse};
+    use ws2mongo::config::{AuthMessageConfig, HmacAlgorithm, SignatureEncoding};
+
+    const FOX: &str = "The quick brown fox jumps over the lazy dog";
+
+    fn auth_config(template: &str) -> AuthMessageConfig {
+        AuthMessageConfig {
+            template: template.to_string(),
+            signature_payload: "{timestamp}{nonce}".to_string(),
+            signature_algorithm: HmacAlgorithm::Sha256,
+            signature_encoding: SignatureEncoding::Hex,
+            success_pointer: Some("/0/msg".to_string()),
+            success_value: "authenticated".to_string(),
+            failure_pointer: Some("/0/T".to_string()),
+            failure_value: "error".to_string(),
+            timeout_ms: 1000,
+        }
+    }
+
+    #[test]
+    fn test_sign_known_vectors() {
+        assert_eq!(
+            sign(HmacAlgorithm::Sha256, SignatureEncoding::Hex, "key", FOX),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+        assert_eq!(
+            sign(HmacAlgorithm::Sha512, SignatureEncoding::Hex, "key", FOX),
+            "b42af09057bac1e2d41708e48a902e09b5ff7f12ab428a4fe86653c73dd248fb\
+             82f948a549f7b791a5b41915ee4d1ec3935357e4e2317250d0372afa2ebeeb3a"
+        );
+        assert_eq!(
+            sign(HmacAlgorithm::Sha256, SignatureEncoding::Base64, "key", FOX),
+            "97yD9DBThCSxMpjmqm+xQ+9NWaFJRhdZl0edvC0aPNg="
+        );
+    }
+
+    #[test]
+    fn test_build_auth_message() {
+        let auth = auth_config(
+            r#"{"op":"auth","key":"{api_key}","ts":{timestamp},"s":{timestamp_s},"nonce":"{nonce}","sig":"{signature}"}"#,
+        );
+        let message = build_auth_message(&auth, "my-key", "key", 1_700_000_000_123, "42");
+        let expected_signature = sign(
+            HmacAlgorithm::Sha256,
+            SignatureEncoding::Hex,
+            "key",
+            "170000000012342",
+        );
+        assert_eq!(
+            message,
+            format!(
+                r#"{{"op":"auth","key":"my-key","ts":1700000000123,"s":1700000000,"nonce":"42","sig":"{}"}}"#,
+                expected_signature
+            )
+        );
+    }
+
+    #[test]
+    fn test_classify_auth_response() {
+        let auth = auth_config("{}");
+        let text = |value: &str| Message::Text(value.to_string());
+
+        assert_eq!(
+            classify_auth_response(&auth, &text(r#"[{"T":"success","msg":"connected"}]"#)),
+            AuthResponse::Unrelated
+        );
+        assert_eq!(
+            classify_auth_response(&auth, &text(r#"[{"T":"success","msg":"authenticated"}]"#)),
+            AuthResponse::Accepted
+        );
+        assert_eq!(
+            classify_auth_response(&auth, &text(r#"[{"T":"error","msg":"auth failed"}]"#)),
+            AuthResponse::Rejected
+        );
+        assert_eq!(
+            classify_auth_response(&auth, &text("not json")),
+            AuthResponse::Unrelated
+        );
+        assert_eq!(
+            classify_auth_response(&auth, &Message::Ping(vec![])),
+            AuthResponse::Unrelated
+        );
+    }
+}
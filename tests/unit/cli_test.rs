@@ -0,0 +1,177 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+#[cfg(test)]
+mod cli_tests {
+    use clap::Parser;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use ws2mongo::cli::{Cli, Command};
+    use ws2mongo::config::{ConfigError, InitialMessage};
+    use ws2mongo::utils::LogLevel;
+
+    fn write_file(name: &str, content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ws2mongo_cli_{}_{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_cli_defaults_to_run() {
+        let cli = Cli::try_parse_from(["ws2mongo"]).unwrap();
+        assert_eq!(cli.command(), Command::Run);
+        assert_eq!(cli.messages, None);
+    }
+
+    #[test]
+    fn test_cli_subcommands_and_global_flags() {
+        let cli = Cli::try_parse_from([
+            "ws2mongo",
+            "probe-ws",
+            "--pipeline",
+            "trades",
+            "-n",
+            "3",
+            "--config",
+            "ws2mongo.toml",
+            "--log-level",
+            "debug",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.command(),
+            Command::ProbeWs {
+                pipeline: Some("trades".to_string()),
+                frames: 3
+            }
+        );
+        assert_eq!(cli.config, Some(PathBuf::from("ws2mongo.toml")));
+        assert_eq!(cli.log_level, LogLevel::Debug);
+
+        let cli =
+            Cli::try_parse_from(["ws2mongo", "-m", "subscribe.ndjson", "check-config"]).unwrap();
+        assert_eq!(cli.command(), Command::CheckConfig);
+        assert_eq!(cli.messages, Some(PathBuf::from("subscribe.ndjson")));
+
+        for (arg, command) in [
+            ("ping-mongo", Command::PingMongo),
+            ("version", Command::Version),
+        ] {
+            assert_eq!(
+                Cli::try_parse_from(["ws2mongo", arg]).unwrap().command(),
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn test_cli_rejects_unknown_input() {
+        assert!(Cli::try_parse_from(["ws2mongo", "ingest"]).is_err());
+        assert!(Cli::try_parse_from(["ws2mongo", "--log-level", "verbose"]).is_err());
+        assert!(Cli::try_parse_from(["ws2mongo", "probe-ws", "-n", "many"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_messages_file_replaces_initial_messages() {
+        let config = write_file(
+            "config.toml",
+            r#"
+database_name = "market"
+websocket_initial_messages = ["from config"]
+
+[pipelines.trades]
+collection_name = "trades"
+
+[pipelines.quotes]
+collection_name = "quotes"
+"#,
+        );
+        let messages = write_file(
+            "messages.ndjson",
+            concat!(
+                "{\"op\":\"subscribe\",\"args\":[\"BTCUSD\"]}\n",
+                "\n",
+                "{\"op\":\"subscribe\",\"args\":[\"ETHUSD\"]}\n",
+            ),
+        );
+        let config_arg = config.to_str().unwrap();
+
+        let cli = Cli::try_parse_from(["ws2mongo", "-c", config_arg]).unwrap();
+        let pipelines = cli.load_pipelines().unwrap();
+        assert_eq!(pipelines.len(), 2);
+        assert!(pipelines
+            .iter()
+            .all(|(_, config)| config.websocket_initial_messages
+                == [InitialMessage::Text("from config".to_string())]));
+
+        let cli = Cli::try_parse_from([
+            "ws2mongo",
+            "-c",
+            config_arg,
+            "-m",
+            messages.to_str().unwrap(),
+        ])
+        .unwrap();
+        for (_, config) in cli.load_pipelines().unwrap() {
+            assert_eq!(
+                config.websocket_initial_messages,
+                [
+                    InitialMessage::Text(r#"{"op":"subscribe","args":["BTCUSD"]}"#.to_string()),
+                    InitialMessage::Text(r#"{"op":"subscribe","args":["ETHUSD"]}"#.to_string()),
+                ]
+            );
+        }
+
+        fs::remove_file(config).unwrap();
+        fs::remove_file(messages).unwrap();
+    }
+
+    #[test]
+    fn test_cli_validates_every_pipeline() {
+        let config = write_file(
+            "invalid.toml",
+            r#"
+database_name = "market"
+
+[pipelines.trades]
+collection_name = "trades"
+
+[pipelines.broken]
+collection_name = "system.trades"
+websocket_url = "http://stream.example.com"
+"#,
+        );
+
+        let cli = Cli::try_parse_from(["ws2mongo", "-c", config.to_str().unwrap()]).unwrap();
+        match cli.load_pipelines() {
+            Err(ConfigError::Pipeline(name, error)) => {
+                assert_eq!(name, "broken");
+                assert!(matches!(*error, ConfigError::Validation(ref errors) if errors.len() == 3));
+            }
+            other => panic!("expected a pipeline validation error, got {:?}", other),
+        }
+
+        fs::remove_file(config).unwrap();
+    }
+}
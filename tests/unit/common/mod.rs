@@ -0,0 +1,110 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+//! Helpers shared by the test targets: lazily connected MongoDB clients and local WebSocket
+//! servers.
+
+// Every test target uses its own subset of the helpers
+#![allow(dead_code)]
+
+use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use ws2mongo::config::Config;
+use ws2mongo::constants::MONGODB_URI;
+use ws2mongo::mongodb::MongoClient;
+
+/// Builds a MongoDB client that never needs a reachable server unless something is written.
+pub async fn lazy_client() -> mongodb::Client {
+    let options = mongodb::options::ClientOptions::parse(MONGODB_URI)
+        .await
+        .unwrap();
+    mongodb::Client::with_options(options).unwrap()
+}
+
+/// Builds a `MongoClient` that never needs a reachable server unless something is enqueued.
+pub async fn lazy_mongo_client(config: &Config) -> Arc<MongoClient> {
+    MongoClient::with_client(&lazy_client().await, config)
+}
+
+/// Starts a WebSocket server on a random local port that sends `greeting` on every connection
+/// and forwards every message it receives on the returned channel. It drops a connection when
+/// it receives the text `bye`.
+pub async fn spawn_recording_server(
+    greeting: Vec<WsMessage>,
+) -> (String, UnboundedReceiver<WsMessage>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            let greeting = greeting.clone();
+            tokio::spawn(async move {
+                let mut socket = accept_async(stream).await.unwrap();
+                for message in greeting {
+                    socket.send(message).await.unwrap();
+                }
+                while let Some(Ok(message)) = socket.next().await {
+                    if message == WsMessage::Text("bye".to_string()) {
+                        break;
+                    }
+                    let _ = tx.send(message);
+                }
+            });
+        }
+    });
+
+    (format!("ws://{}", address), rx)
+}
+
+/// Waits for the next message the server receives, skipping the given kind of frames.
+pub async fn next_received(
+    received: &mut UnboundedReceiver<WsMessage>,
+    skip: fn(&WsMessage) -> bool,
+) -> WsMessage {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let message = received.recv().await.unwrap();
+            if !skip(&message) {
+                return message;
+            }
+        }
+    })
+    .await
+    .expect("the server should receive a message")
+}
+
+/// Waits for the next text message the server receives, skipping control frames.
+pub async fn next_text(received: &mut UnboundedReceiver<WsMessage>) -> String {
+    let is_control = |message: &WsMessage| !matches!(message, WsMessage::Text(_));
+    next_received(received, is_control)
+        .await
+        .into_text()
+        .unwrap()
+}
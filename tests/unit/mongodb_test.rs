@@ -0,0 +1,816 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 11/5/24
+******************************************************************************/
+
+#[cfg(test)]
+mod mongodb_tests {
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::{doc, Bson, DateTime, Decimal128};
+    use mongodb::error::{BulkWriteFailure, Error as MongoError, ErrorKind};
+    use mongodb::options::IndexOptions;
+    use mongodb::options::{AuthMechanism, ClientOptions, TimeseriesGranularity, Tls, TlsOptions};
+    use mongodb::IndexModel;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+    use tokio_tungstenite::tungstenite::protocol::frame::{Frame, FrameHeader};
+    use tokio_tungstenite::tungstenite::Message;
+    use ws2mongo::config::{
+        Coercion, CollectionRoute, Config, EnvelopeMode, ErrorPolicy, Granularity, IndexSpec,
+        TimeUnit, WriteMode,
+    };
+    use ws2mongo::constants::MONGODB_URI;
+    use ws2mongo::mongodb::{
+        checked_route_collection, coerce, credential, dead_letter_document, declared_indexes,
+        failed_documents, plan_indexes, route_collection, tls, to_datetime, DocumentBatch,
+        Envelope, MessageOrigin, MongoClient, MongoClientError, Timeseries, WriteKey,
+    };
+    use ws2mongo::utils::DecodeError;
+
+    fn frame() -> Arc<Message> {
+        Arc::new(Message::Text("{}".to_string()))
+    }
+
+    #[test]
+    fn test_batch_fills_up_to_max_size() {
+        let mut batch = DocumentBatch::new(3, Duration::from_secs(1));
+        assert!(batch.is_empty());
+        assert!(batch.deadline().is_none());
+
+        batch.push(doc! {"n": 1}, DateTime::now(), frame());
+        batch.push(doc! {"n": 2}, DateTime::now(), frame());
+        assert!(!batch.is_full());
+        assert!(batch.deadline().is_some());
+
+        batch.push(doc! {"n": 3}, DateTime::now(), frame());
+        assert!(batch.is_full());
+        assert_eq!(batch.len(), 3);
+    }
+
+    #[test]
+    fn test_batch_take_resets() {
+        let mut batch = DocumentBatch::new(2, Duration::from_secs(1));
+        batch.push(doc! {"n": 1}, DateTime::now(), frame());
+        batch.push(doc! {"n": 2}, DateTime::now(), frame());
+
+        let documents: Vec<_> = batch
+            .take()
+            .into_iter()
+            .map(|(document, _, _)| document)
+            .collect();
+        assert_eq!(documents, vec![doc! {"n": 1}, doc! {"n": 2}]);
+        assert!(batch.is_empty());
+        assert!(batch.deadline().is_none());
+    }
+
+    #[test]
+    fn test_batch_groups_documents_by_collection() {
+        let mut batch = DocumentBatch::new(4, Duration::from_secs(1));
+        batch.push_to(
+            Some("trades".to_string()),
+            doc! {"n": 1},
+            DateTime::now(),
+            frame(),
+        );
+        batch.push(doc! {"n": 2}, DateTime::now(), frame());
+        batch.push_to(
+            Some("trades".to_string()),
+            doc! {"n": 3},
+            DateTime::now(),
+            frame(),
+        );
+        batch.push_to(
+            Some("quotes".to_string()),
+            doc! {"n": 4},
+            DateTime::now(),
+            frame(),
+        );
+        assert!(batch.is_full());
+
+        let groups: Vec<_> = batch
+            .take_by_collection()
+            .into_iter()
+            .map(|(collection, entries)| {
+                let numbers: Vec<_> = entries
+                    .into_iter()
+                    .map(|(document, _, _)| document.get_i32("n").unwrap())
+                    .collect();
+                (collection, numbers)
+            })
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                (Some("trades".to_string()), vec![1, 3]),
+                (None, vec![2]),
+                (Some("quotes".to_string()), vec![4]),
+            ]
+        );
+        assert!(batch.is_empty());
+        assert!(batch.take_by_collection().is_empty());
+    }
+
+    #[test]
+    fn test_batch_keeps_the_raw_frame_of_every_document() {
+        // Both items of an array frame point back to that frame, for dead-lettering
+        let array = Arc::new(Message::Text(r#"[{"n": 1}, {"n": 2}]"#.to_string()));
+        let mut batch = DocumentBatch::new(3, Duration::from_secs(1));
+        batch.push(doc! {"n": 1}, DateTime::now(), Arc::clone(&array));
+        batch.push_to(
+            Some("trades".to_string()),
+            doc! {"n": 2},
+            DateTime::now(),
+            Arc::clone(&array),
+        );
+        batch.push(doc! {"n": 3}, DateTime::now(), frame());
+
+        let frames: Vec<_> = batch
+            .take_by_collection()
+            .into_iter()
+            .flat_map(|(_, entries)| entries.into_iter().map(|(_, _, frame)| frame))
+            .collect();
+        assert!(Arc::ptr_eq(&frames[0], &array));
+        assert_eq!(*frames[1], Message::Text("{}".to_string()));
+        assert!(Arc::ptr_eq(&frames[2], &array));
+    }
+
+    #[test]
+    fn test_route_collection_uses_the_first_match() {
+        let routes = [
+            CollectionRoute::from_json(json!({
+                "pointer": "/T",
+                "collections": {"t": "alpaca_trades", "q": "alpaca_quotes"}
+            }))
+            .unwrap(),
+            CollectionRoute::Template("{stream}_{symbol}".to_string()),
+        ];
+        assert_eq!(
+            route_collection(&routes, &json!({"T": "q", "stream": "x", "symbol": "y"})).as_deref(),
+            Some("alpaca_quotes")
+        );
+        assert_eq!(
+            route_collection(
+                &routes,
+                &json!({"T": "b", "stream": "bars", "symbol": "AAPL"})
+            )
+            .as_deref(),
+            Some("bars_AAPL")
+        );
+        assert_eq!(route_collection(&routes, &json!({"T": "b"})), None);
+        assert_eq!(route_collection(&[], &json!({"T": "t"})), None);
+    }
+
+    #[test]
+    fn test_checked_route_collection_rejects_invalid_names() {
+        let routes = [CollectionRoute::Template("{stream}_{symbol}".to_string())];
+        let route =
+            |payload: serde_json::Value| checked_route_collection(&routes, "market", &payload);
+        assert_eq!(
+            route(json!({"stream": "bars", "symbol": "AAPL"}))
+                .unwrap()
+                .as_deref(),
+            Some("bars_AAPL")
+        );
+        assert_eq!(route(json!({"T": "b"})).unwrap(), None);
+
+        for payload in [
+            json!({"stream": "bars", "symbol": "$where"}),
+            json!({"stream": "bars", "symbol": "A\u{0}B"}),
+            json!({"stream": "system.bars", "symbol": "AAPL"}),
+            json!({"stream": "bars", "symbol": "x".repeat(260)}),
+        ] {
+            assert!(matches!(
+                route(payload),
+                Err(MongoClientError::InvalidCollection(_, _))
+            ));
+        }
+        // Empty fields match no rule rather than making an empty name
+        assert_eq!(
+            route(json!({"stream": "", "symbol": "AAPL"})).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_batch_zero_size_is_clamped() {
+        let mut batch = DocumentBatch::new(0, Duration::from_secs(1));
+        batch.push(doc! {"n": 1}, DateTime::now(), frame());
+        assert!(batch.is_full());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_deadline_follows_first_document() {
+        let timeout = Duration::from_millis(500);
+        let mut batch = DocumentBatch::new(10, timeout);
+
+        batch.push(doc! {"n": 1}, DateTime::now(), frame());
+        let deadline = batch.deadline().unwrap();
+        tokio::time::advance(Duration::from_millis(200)).await;
+        batch.push(doc! {"n": 2}, DateTime::now(), frame());
+
+        assert_eq!(batch.deadline().unwrap(), deadline);
+        assert_eq!(
+            deadline - tokio::time::Instant::now(),
+            Duration::from_millis(300)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_rejects_undecodable_messages() {
+        let options = ClientOptions::parse(MONGODB_URI).await.unwrap();
+        let client = mongodb::Client::with_options(options).unwrap();
+        let mongo_client = MongoClient::with_client(&client, &Config::default());
+
+        let header = FrameHeader {
+            opcode: OpCode::Data(Data::Text),
+            ..Default::default()
+        };
+        let error = mongo_client
+            .enqueue(Message::Text("not json".to_string()))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            MongoClientError::Decode(DecodeError::Json(_))
+        ));
+
+        let frame = Message::Frame(Frame::from_payload(header, b"{}".to_vec()));
+        let error = mongo_client.enqueue(frame).await.unwrap_err();
+        assert!(matches!(
+            error,
+            MongoClientError::Decode(DecodeError::UnsupportedFrame(_))
+        ));
+        assert!(matches!(
+            ws2mongo::error::Error::from(error),
+            ws2mongo::error::Error::Mongo(_)
+        ));
+    }
+
+    #[test]
+    fn test_dead_letter_document() {
+        let received_at = DateTime::from_millis(1_700_000_000_000);
+        let error = MongoClientError::NotADocument("42".to_string());
+
+        let document = dead_letter_document(
+            &Message::Text("42".to_string()),
+            &error,
+            "wss://example.com/feed",
+            received_at,
+        );
+        assert_eq!(document.get_str("payload").unwrap(), "42");
+        assert_eq!(document.get_str("payload_encoding").unwrap(), "text");
+        assert_eq!(document.get_str("error_kind").unwrap(), "not_a_document");
+        assert_eq!(document.get_str("error").unwrap(), error.to_string());
+        assert_eq!(
+            document.get_str("source").unwrap(),
+            "wss://example.com/feed"
+        );
+        assert_eq!(*document.get_datetime("received_at").unwrap(), received_at);
+
+        let document = dead_letter_document(
+            &Message::Binary(vec![0xff, 0x00]),
+            &MongoClientError::Insert("duplicate key".to_string()),
+            "wss://example.com/feed",
+            received_at,
+        );
+        assert_eq!(document.get_str("payload").unwrap(), "/wA=");
+        assert_eq!(document.get_str("payload_encoding").unwrap(), "base64");
+        assert_eq!(document.get_str("error_kind").unwrap(), "insert");
+    }
+
+    #[test]
+    fn test_failed_documents_of_a_partial_insert() {
+        // An unordered insert_many of 4 documents where the 2nd and 4th reuse an existing _id
+        let failure: BulkWriteFailure = mongodb::bson::from_document(doc! {
+            "writeErrors": [
+                {"index": 1, "code": 11000, "errmsg": "E11000 duplicate key error"},
+                {"index": 3, "code": 11000, "errmsg": "E11000 duplicate key error"},
+            ]
+        })
+        .unwrap();
+        let error = MongoError::from(ErrorKind::BulkWrite(failure));
+        let failures = failed_documents(&error, 4);
+        assert_eq!(
+            failures,
+            [
+                (1, Some(11000), "E11000 duplicate key error".to_string()),
+                (3, Some(11000), "E11000 duplicate key error".to_string()),
+            ]
+        );
+
+        // Without per-document errors the outcome of every document is unknown
+        let error = MongoError::from(std::io::Error::other("connection reset"));
+        let failures = failed_documents(&error, 3);
+        assert_eq!(
+            failures
+                .iter()
+                .map(|(index, _, _)| *index)
+                .collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert!(failures.iter().all(|(_, code, _)| code.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_write_error_abort_stops_the_writer() {
+        let options = ClientOptions::parse(MONGODB_URI).await.unwrap();
+        let client = mongodb::Client::with_options(options).unwrap();
+        let config = Config {
+            write_error_policy: ErrorPolicy::Abort,
+            ..Default::default()
+        };
+        let mongo_client = MongoClient::with_client(&client, &config);
+
+        mongo_client
+            .enqueue(Message::Text("42".to_string()))
+            .await
+            .unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Err(e) = mongo_client.enqueue(Message::Text("{}".to_string())).await {
+                    return e;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the writer should close the channel");
+        assert!(matches!(result, MongoClientError::Enqueue(_)));
+    }
+
+    #[test]
+    fn test_envelope_from_config() {
+        assert!(Envelope::from_config(&Config::default()).is_none());
+
+        let config = Config {
+            websocket_url: "wss://example.com/feed".to_string(),
+            envelope_mode: EnvelopeMode::Wrap,
+            ..Default::default()
+        };
+        let envelope = Envelope::from_config(&config).unwrap();
+        assert_eq!(envelope.key, "payload");
+        assert_eq!(envelope.source, "wss://example.com/feed");
+
+        let config = Config {
+            envelope_mode: EnvelopeMode::Merge,
+            envelope_source: Some("trades".to_string()),
+            ..config
+        };
+        assert_eq!(Envelope::from_config(&config).unwrap().source, "trades");
+    }
+
+    #[test]
+    fn test_envelope_wraps_and_merges() {
+        let received_at = DateTime::from_millis(1_700_000_000_000);
+        let origin = MessageOrigin {
+            connection_id: ObjectId::new(),
+            seq: 7,
+        };
+        let mut envelope = Envelope {
+            mode: EnvelopeMode::Wrap,
+            key: "data".to_string(),
+            source: "trades".to_string(),
+        };
+
+        let wrapped = envelope.apply(doc! {"p": 1.5}, received_at, "text", Some(origin));
+        assert_eq!(
+            wrapped,
+            doc! {
+                "data": {"p": 1.5},
+                "_received_at": received_at,
+                "_source": "trades",
+                "_frame_type": "text",
+                "_connection_id": origin.connection_id,
+                "_seq": 7_i64,
+            }
+        );
+
+        envelope.mode = EnvelopeMode::Merge;
+        let payload = doc! {"p": 1.5, "_source": "upstream"};
+        let merged = envelope.apply(payload, received_at, "binary", None);
+        assert_eq!(
+            merged,
+            doc! {
+                "p": 1.5,
+                "_source": "trades",
+                "_received_at": received_at,
+                "_frame_type": "binary",
+            }
+        );
+    }
+
+    #[test]
+    fn test_credential_from_config() {
+        assert!(credential(&Config::default()).unwrap().is_none());
+
+        let config = Config {
+            mongodb_user: Some("admin".to_string()),
+            mongodb_password: Some("password".into()),
+            ..Default::default()
+        };
+        let scram = credential(&config).unwrap().unwrap();
+        assert_eq!(scram.username.as_deref(), Some("admin"));
+        assert_eq!(scram.password.as_deref(), Some("password"));
+        assert_eq!(scram.source.as_deref(), Some("admin"));
+        assert_eq!(scram.mechanism, Some(AuthMechanism::ScramSha256));
+
+        let unknown = Config {
+            mongodb_auth_mechanism: "SCRAM-SHA-512".to_string(),
+            ..config
+        };
+        assert!(matches!(
+            credential(&unknown),
+            Err(MongoClientError::UnsupportedAuthMechanism(ref mechanism))
+                if mechanism == "SCRAM-SHA-512"
+        ));
+    }
+
+    #[test]
+    fn test_credential_for_aws_and_gssapi() {
+        // MONGODB-AWS can take its keys from the AWS environment, so no user is needed
+        let aws = Config {
+            mongodb_auth_mechanism: "MONGODB-AWS".to_string(),
+            mongodb_aws_session_token: Some("token".into()),
+            ..Default::default()
+        };
+        let gssapi = Config {
+            mongodb_auth_mechanism: "GSSAPI".to_string(),
+            mongodb_user: Some("ingest@EXAMPLE.COM".to_string()),
+            ..Default::default()
+        };
+
+        #[cfg(feature = "aws-auth")]
+        {
+            let credential = credential(&aws).unwrap().unwrap();
+            assert_eq!(credential.mechanism, Some(AuthMechanism::MongoDbAws));
+            assert_eq!(credential.source, None);
+            assert_eq!(
+                credential.mechanism_properties,
+                Some(doc! {"AWS_SESSION_TOKEN": "token"})
+            );
+        }
+        #[cfg(not(feature = "aws-auth"))]
+        assert!(matches!(
+            credential(&aws),
+            Err(MongoClientError::UnsupportedAuthMechanism(_))
+        ));
+
+        assert!(matches!(
+            credential(&gssapi),
+            Err(MongoClientError::UnsupportedAuthMechanism(_))
+        ));
+    }
+
+    #[test]
+    fn test_credential_for_x509_without_user() {
+        let config = Config {
+            mongodb_auth_mechanism: "MONGODB-X509".to_string(),
+            ..Default::default()
+        };
+        let credential = credential(&config).unwrap().unwrap();
+        assert_eq!(credential.mechanism, Some(AuthMechanism::MongoDbX509));
+        assert_eq!(credential.username, None);
+        assert_eq!(credential.source, None);
+    }
+
+    #[test]
+    fn test_tls_from_config() {
+        let mut uri_options = TlsOptions::default();
+        uri_options.ca_file_path = Some(PathBuf::from("/uri/ca.pem"));
+        let uri_tls = Some(Tls::Enabled(uri_options));
+
+        // Without TLS settings, the URI decides
+        assert_eq!(tls(&Config::default(), None), None);
+        assert_eq!(tls(&Config::default(), uri_tls.clone()), uri_tls);
+
+        let disabled = Config {
+            mongodb_tls: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(tls(&disabled, uri_tls.clone()), Some(Tls::Disabled));
+
+        let enabled = Config {
+            mongodb_tls: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            tls(&enabled, None),
+            Some(Tls::Enabled(TlsOptions::default()))
+        );
+
+        let certificate = Config {
+            mongodb_tls_cert_key_file: Some("/etc/ssl/ingest.pem".to_string()),
+            ..Default::default()
+        };
+        let Some(Tls::Enabled(options)) = tls(&certificate, uri_tls) else {
+            panic!("expected TLS to be enabled");
+        };
+        assert_eq!(options.ca_file_path, Some(PathBuf::from("/uri/ca.pem")));
+        assert_eq!(
+            options.cert_key_file_path,
+            Some(PathBuf::from("/etc/ssl/ingest.pem"))
+        );
+
+        let ca = Config {
+            mongodb_tls_ca_file: Some("/etc/ssl/ca.pem".to_string()),
+            ..certificate
+        };
+        let Some(Tls::Enabled(options)) = tls(&ca, None) else {
+            panic!("expected TLS to be enabled");
+        };
+        assert_eq!(options.ca_file_path, Some(PathBuf::from("/etc/ssl/ca.pem")));
+    }
+
+    #[test]
+    fn test_to_datetime() {
+        let expected = DateTime::from_millis(1_700_000_000_123);
+        for (value, unit) in [
+            (json!(1_700_000_000_123_i64), TimeUnit::Millis),
+            (json!(1_700_000_000.123), TimeUnit::Seconds),
+            (json!(1_700_000_000_123_456_i64), TimeUnit::Micros),
+            (json!(1_700_000_000_123_456_789_i64), TimeUnit::Nanos),
+            (json!("1700000000123"), TimeUnit::Millis),
+            (json!("2023-11-14T22:13:20.123Z"), TimeUnit::Seconds),
+            (json!("2023-11-14T23:13:20.123+01:00"), TimeUnit::Millis),
+        ] {
+            assert_eq!(
+                to_datetime(&value, unit),
+                Some(expected),
+                "{} in {}",
+                value,
+                unit
+            );
+        }
+        assert_eq!(
+            to_datetime(&json!(1_700_000_000), TimeUnit::Seconds),
+            Some(DateTime::from_millis(1_700_000_000_000))
+        );
+
+        assert_eq!(to_datetime(&json!("yesterday"), TimeUnit::Millis), None);
+        assert_eq!(to_datetime(&json!(true), TimeUnit::Millis), None);
+        assert_eq!(to_datetime(&json!(i64::MAX), TimeUnit::Seconds), None);
+        assert_eq!(to_datetime(&json!(1e300), TimeUnit::Seconds), None);
+    }
+
+    #[test]
+    fn test_timeseries_from_config() {
+        assert!(Timeseries::from_config(&Config::default()).is_none());
+
+        let config = Config {
+            timeseries_time_field: Some("ts".to_string()),
+            timeseries_time_pointer: Some("/t".to_string()),
+            timeseries_time_unit: TimeUnit::Seconds,
+            timeseries_meta_field: Some("symbol".to_string()),
+            timeseries_granularity: Some(Granularity::Minutes),
+            timeseries_expire_after_seconds: Some(86_400),
+            ..Default::default()
+        };
+        let timeseries = Timeseries::from_config(&config).unwrap();
+        let options = timeseries.create_options();
+        assert_eq!(
+            options.expire_after_seconds,
+            Some(Duration::from_secs(86_400))
+        );
+        let options = options.timeseries.unwrap();
+        assert_eq!(options.time_field, "ts");
+        assert_eq!(options.meta_field.as_deref(), Some("symbol"));
+        assert_eq!(options.granularity, Some(TimeseriesGranularity::Minutes));
+
+        let received_at = DateTime::from_millis(42);
+        let mut document = doc! {"t": 1_700_000_000_i64, "symbol": "BTCUSD"};
+        timeseries
+            .apply(&mut document, &json!({"t": 1_700_000_000_i64}), received_at)
+            .unwrap();
+        assert_eq!(
+            document.get_datetime("ts").unwrap().timestamp_millis(),
+            1_700_000_000_000
+        );
+
+        let mut document = doc! {};
+        for payload in [json!({"t": "soon"}), json!({"price": 1})] {
+            assert!(matches!(
+                timeseries.apply(&mut document, &payload, received_at),
+                Err(MongoClientError::TimeField(_))
+            ));
+        }
+
+        // Without a pointer the receive time is used
+        let received = Timeseries {
+            time_pointer: None,
+            ..timeseries
+        };
+        received
+            .apply(&mut document, &json!({}), received_at)
+            .unwrap();
+        assert_eq!(document.get_datetime("ts").unwrap(), &received_at);
+    }
+
+    fn coercions(rules: &[(&str, &str)]) -> BTreeMap<String, Coercion> {
+        rules
+            .iter()
+            .map(|(pointer, coercion)| (pointer.to_string(), coercion.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_coerce_fields() {
+        let id = ObjectId::new();
+        let mut document = mongodb::bson::to_document(&json!({
+            "t": 1_700_000_000_123_456_789_i64,
+            "iso": "2023-11-14T22:13:20.123Z",
+            "p": 0.1,
+            "size": "12.3450",
+            "id": id.to_hex(),
+            "n": 7.0,
+            "seq": "9007199254740993",
+            "levels": [["100.5", "2"], ["100.25", "1"]],
+            "missing_parent": null,
+        }))
+        .unwrap();
+        let rules = coercions(&[
+            ("/t", "datetime:ns"),
+            ("/iso", "datetime"),
+            ("/p", "decimal128"),
+            ("/size", "decimal"),
+            ("/id", "objectid"),
+            ("/n", "int32"),
+            ("/seq", "int64"),
+            ("/levels/*/0", "decimal128"),
+            ("/missing_parent/x", "int32"),
+            ("/absent", "objectid"),
+        ]);
+        coerce(&mut document, &rules).unwrap();
+
+        let expected_time = DateTime::from_millis(1_700_000_000_123);
+        assert_eq!(document.get_datetime("t").unwrap(), &expected_time);
+        assert_eq!(document.get_datetime("iso").unwrap(), &expected_time);
+        let decimal = |text: &str| Bson::Decimal128(text.parse::<Decimal128>().unwrap());
+        assert_eq!(document.get("p"), Some(&decimal("0.1")));
+        assert_eq!(document.get("size"), Some(&decimal("12.3450")));
+        assert_eq!(document.get_object_id("id").unwrap(), id);
+        assert_eq!(document.get_i32("n").unwrap(), 7);
+        assert_eq!(document.get_i64("seq").unwrap(), 9_007_199_254_740_993);
+        let levels = document.get_array("levels").unwrap();
+        assert_eq!(
+            levels[1],
+            Bson::Array(vec![decimal("100.25"), Bson::String("1".into())])
+        );
+        assert!(!document.contains_key("absent"));
+
+        // Coercing again is a no-op
+        let coerced = document.clone();
+        coerce(&mut document, &rules).unwrap();
+        assert_eq!(document, coerced);
+    }
+
+    #[test]
+    fn test_coerce_rejects_invalid_values() {
+        for (value, coercion) in [
+            (json!("not a date"), "datetime"),
+            (json!("12,5"), "decimal128"),
+            (json!(true), "decimal128"),
+            (json!("zz"), "objectid"),
+            (json!(1.5), "int64"),
+            (json!(3_000_000_000_i64), "int32"),
+        ] {
+            let mut document = mongodb::bson::to_document(&json!({"field": value})).unwrap();
+            let original = document.clone();
+            match coerce(&mut document, &coercions(&[("/field", coercion)])) {
+                Err(MongoClientError::Coerce(reason)) => assert!(reason.ends_with("at /field")),
+                other => panic!("expected a coercion error for {}, got {:?}", value, other),
+            }
+            // The document is left unchanged
+            assert_eq!(document, original);
+        }
+    }
+
+    #[test]
+    fn test_write_key_from_config() {
+        assert!(WriteKey::from_config(&Config::default()).is_none());
+
+        let config = Config {
+            write_mode: WriteMode::Upsert,
+            write_key_fields: vec!["symbol".to_string(), "payload.i".to_string()],
+            write_key_unique: true,
+            ..Default::default()
+        };
+        let key = WriteKey::from_config(&config).unwrap();
+        assert_eq!(key.mode, WriteMode::Upsert);
+        assert!(key.unique);
+
+        let index = key.index();
+        assert_eq!(index.keys, doc! {"symbol": 1, "payload.i": 1});
+        assert_eq!(index.options.unwrap().unique, Some(true));
+    }
+
+    #[test]
+    fn test_write_key_filter() {
+        let key = WriteKey {
+            mode: WriteMode::Replace,
+            fields: vec!["symbol".to_string(), "payload.i".to_string()],
+            unique: false,
+        };
+        let document = doc! {"symbol": "BTCUSD", "payload": {"i": 42_i64, "p": 1.5}};
+        assert_eq!(
+            key.filter(&document).unwrap(),
+            doc! {"symbol": "BTCUSD", "payload.i": 42_i64}
+        );
+
+        for document in [
+            doc! {"payload": {"i": 42_i64}},
+            doc! {"symbol": "BTCUSD", "payload": {"p": 1.5}},
+            doc! {"symbol": "BTCUSD", "payload": 42_i64},
+            doc! {"symbol": null, "payload": {"i": 42_i64}},
+        ] {
+            assert!(matches!(
+                key.filter(&document),
+                Err(MongoClientError::MissingKey(_))
+            ));
+        }
+    }
+
+    fn index(name: &str, keys: mongodb::bson::Document, unique: bool) -> IndexModel {
+        let options = IndexOptions::builder()
+            .name(name.to_string())
+            .unique(unique)
+            .build();
+        IndexModel::builder().keys(keys).options(options).build()
+    }
+
+    #[test]
+    fn test_declared_indexes() {
+        assert!(declared_indexes(&Config::default()).is_empty());
+
+        let ttl = IndexSpec::from_json(json!({
+            "keys": {"_received_at": 1},
+            "expire_after_seconds": 86400
+        }))
+        .unwrap();
+        let config = Config {
+            mongodb_indexes: vec![ttl],
+            write_key_fields: vec!["trade_id".to_string()],
+            write_key_unique: true,
+            ..Default::default()
+        };
+        let indexes = declared_indexes(&config);
+        assert_eq!(indexes.len(), 2);
+        let options = indexes[0].options.clone().unwrap();
+        assert_eq!(options.name.as_deref(), Some("_received_at_1"));
+        assert_eq!(options.expire_after, Some(Duration::from_secs(86400)));
+        assert_eq!(indexes[1].keys, doc! {"trade_id": 1});
+    }
+
+    #[test]
+    fn test_plan_indexes() {
+        let existing = vec![
+            index("_id_", doc! {"_id": 1}, false),
+            index("symbol_1_ts_-1", doc! {"symbol": 1.0, "ts": -1}, false),
+            index("legacy", doc! {"price": 1}, false),
+        ];
+        // Matched by name, with keys stored as doubles, by keys under another name, and missing
+        let declared = vec![
+            index("symbol_1_ts_-1", doc! {"symbol": 1, "ts": -1}, false),
+            index("by_price", doc! {"price": 1_i64}, false),
+            index("trade_id_1", doc! {"trade_id": 1}, true),
+        ];
+        let plan = plan_indexes(&existing, &declared).unwrap();
+        assert_eq!(plan.create.len(), 1);
+        assert_eq!(plan.create[0].keys, doc! {"trade_id": 1});
+        assert!(plan.undeclared.is_empty());
+
+        let plan = plan_indexes(&existing, &declared[2..]).unwrap();
+        assert_eq!(plan.undeclared, ["symbol_1_ts_-1", "legacy"]);
+
+        // Same keys, different options
+        let unique_price = [index("price_1", doc! {"price": 1}, true)];
+        let reason = plan_indexes(&existing, &unique_price).unwrap_err();
+        assert!(reason.contains("legacy"), "{}", reason);
+
+        // Same name, different keys
+        let renamed = [index("legacy", doc! {"price": -1}, false)];
+        assert!(plan_indexes(&existing, &renamed).is_err());
+    }
+}
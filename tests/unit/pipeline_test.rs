@@ -0,0 +1,199 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+mod common;
+
+use common::{lazy_client, next_text, spawn_recording_server};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+/// Starts a WebSocket server that pings every client once, see `spawn_recording_server`.
+async fn spawn_pinging_server() -> (String, tokio::sync::mpsc::UnboundedReceiver<WsMessage>) {
+    spawn_recording_server(vec![WsMessage::Ping(vec![1])]).await
+}
+
+#[cfg(test)]
+mod pipeline_tests {
+    use super::*;
+    use std::time::Duration;
+    use ws2mongo::config::{Config, InitialMessage};
+    use ws2mongo::pipeline::{
+        ClientKey, Pipeline, PipelineState, PipelineStatus, PipelineSupervisor,
+    };
+
+    fn pipeline(name: &str, url: &str, subscribe: &str) -> Pipeline {
+        let config = Config {
+            websocket_url: url.to_string(),
+            websocket_initial_messages: vec![InitialMessage::Text(subscribe.to_string())],
+            collection_name: name.to_string(),
+            ..Default::default()
+        };
+        Pipeline::new(name, config)
+    }
+
+    /// Polls the supervisor until `predicate` holds for the status of the named pipeline.
+    async fn wait_for_status(
+        supervisor: &PipelineSupervisor,
+        name: &str,
+        predicate: impl Fn(&PipelineStatus) -> bool,
+    ) -> PipelineStatus {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let status = supervisor
+                    .status()
+                    .into_iter()
+                    .find(|status| status.name == name)
+                    .unwrap();
+                if predicate(&status) {
+                    return status;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the pipeline should reach the expected status")
+    }
+
+    #[tokio::test]
+    async fn test_pipelines_run_side_by_side() {
+        let (trades_url, mut trades) = spawn_pinging_server().await;
+        let (quotes_url, mut quotes) = spawn_pinging_server().await;
+        let supervisor = PipelineSupervisor::start_with_client(
+            vec![
+                pipeline("trades", &trades_url, r#"{"subscribe":"trades"}"#),
+                pipeline("quotes", &quotes_url, r#"{"subscribe":"quotes"}"#),
+            ],
+            &lazy_client().await,
+        );
+
+        assert_eq!(next_text(&mut trades).await, r#"{"subscribe":"trades"}"#);
+        assert_eq!(next_text(&mut quotes).await, r#"{"subscribe":"quotes"}"#);
+
+        for name in ["trades", "quotes"] {
+            let status =
+                wait_for_status(&supervisor, name, |status| status.messages_received > 0).await;
+            assert_eq!(status.state, PipelineState::Running);
+            assert!(status.connected);
+            assert_eq!(status.connections, 1);
+            assert_eq!(status.idle_timeouts, 0);
+        }
+        assert!(!supervisor.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_failed_pipeline_does_not_stop_the_others() {
+        let (url, mut received) = spawn_pinging_server().await;
+        let supervisor = PipelineSupervisor::start_with_client(
+            vec![
+                pipeline("broken", "not a url", "{}"),
+                pipeline("healthy", &url, r#"{"subscribe":"bars"}"#),
+            ],
+            &lazy_client().await,
+        );
+
+        let status = wait_for_status(&supervisor, "broken", |status| {
+            status.state != PipelineState::Running
+        })
+        .await;
+        assert!(matches!(status.state, PipelineState::Failed(_)));
+        assert!(status.to_string().starts_with("broken: failed: "));
+
+        assert_eq!(next_text(&mut received).await, r#"{"subscribe":"bars"}"#);
+        let status = wait_for_status(&supervisor, "healthy", |status| status.connected).await;
+        assert_eq!(status.state, PipelineState::Running);
+        assert!(!supervisor.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_wait_returns_once_every_pipeline_finished() {
+        let supervisor = PipelineSupervisor::start_with_client(
+            vec![
+                pipeline("first", "not a url", "{}"),
+                pipeline("second", "unix:/var/run/feed.sock", "{}"),
+            ],
+            &lazy_client().await,
+        );
+
+        tokio::time::timeout(Duration::from_secs(5), supervisor.wait())
+            .await
+            .expect("both pipelines should fail");
+        assert!(supervisor.is_finished());
+        assert!(supervisor
+            .status()
+            .iter()
+            .all(|status| matches!(status.state, PipelineState::Failed(_))));
+    }
+
+    #[test]
+    fn test_client_key_covers_tls_settings() {
+        let config = Config {
+            mongodb_uri: "mongodb://db.example.net:27017".into(),
+            mongodb_auth_mechanism: "MONGODB-X509".to_string(),
+            mongodb_tls: Some(true),
+            mongodb_tls_cert_key_file: Some("/etc/ws2mongo/trades.pem".to_string()),
+            ..Default::default()
+        };
+        let trades = Pipeline::new("trades", config.clone());
+        let quotes = Pipeline::new(
+            "quotes",
+            Config {
+                mongodb_tls_cert_key_file: Some("/etc/ws2mongo/quotes.pem".to_string()),
+                ..config.clone()
+            },
+        );
+        // Different client certificates authenticate as different users
+        assert_ne!(
+            ClientKey::from_config(&trades.config),
+            ClientKey::from_config(&quotes.config)
+        );
+
+        let same = Pipeline::new("trades-copy", config.clone());
+        assert_eq!(
+            ClientKey::from_config(&trades.config),
+            ClientKey::from_config(&same.config)
+        );
+
+        for other in [
+            Config {
+                mongodb_tls_ca_file: Some("/etc/ssl/ca.pem".to_string()),
+                ..config.clone()
+            },
+            Config {
+                mongodb_tls_allow_invalid_hostnames: true,
+                ..config.clone()
+            },
+            Config {
+                mongodb_tls: Some(false),
+                ..config.clone()
+            },
+            Config {
+                mongodb_aws_session_token: Some("token".into()),
+                ..config.clone()
+            },
+        ] {
+            assert_ne!(
+                ClientKey::from_config(&config),
+                ClientKey::from_config(&other)
+            );
+        }
+    }
+}
@@ -0,0 +1,242 @@
+/*******************************************************************************
+ * Copyright (c) 2024.
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the
+ * Free Software Foundation, either version 3 of the License, or (at your
+ * option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General
+ * Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>..
+ ******************************************************************************/
+
+/******************************************************************************
+   Author:
+   Email: jb@taunais.com
+   Date: 18/10/26
+******************************************************************************/
+
+mod common;
+
+use common::{lazy_mongo_client, next_text, spawn_recording_server};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+#[cfg(test)]
+mod subscription_tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use ws2mongo::config::Config;
+    use ws2mongo::subscription::{SubscriptionError, SubscriptionSet};
+    use ws2mongo::websocket::WebSocketClient;
+
+    fn topics(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn subscription_config() -> Config {
+        Config {
+            websocket_subscribe_template: Some("sub:{symbol}".to_string()),
+            websocket_unsubscribe_template: Some("unsub:{symbol}".to_string()),
+            websocket_subscribe_symbols: topics(&["BTCUSD", "ETHUSD", "BTCUSD"]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_subscription_set_tracks_topics() {
+        let mut set = SubscriptionSet::from_config(&subscription_config());
+        assert_eq!(set.topics(), ["BTCUSD", "ETHUSD"]);
+
+        assert_eq!(set.insert(&topics(&["ETHUSD", "SOLUSD"])), ["SOLUSD"]);
+        assert_eq!(set.remove(&topics(&["BTCUSD", "XRPUSD"])), ["BTCUSD"]);
+        assert_eq!(set.topics(), ["ETHUSD", "SOLUSD"]);
+        assert_eq!(
+            set.replay_messages(),
+            vec![
+                WsMessage::Text("sub:ETHUSD".to_string()),
+                WsMessage::Text("sub:SOLUSD".to_string())
+            ]
+        );
+        assert_eq!(
+            set.unsubscribe_messages(&topics(&["ETHUSD"])).unwrap(),
+            vec![WsMessage::Text("unsub:ETHUSD".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_subscription_set_messages() {
+        let config = Config {
+            websocket_subscribe_template: Some(
+                r#"{"op":"subscribe","args":{symbols}}"#.to_string(),
+            ),
+            websocket_subscribe_binary: true,
+            ..Default::default()
+        };
+        let mut set = SubscriptionSet::from_config(&config);
+        assert!(set.replay_messages().is_empty());
+
+        set.insert(&topics(&["BTCUSD", "ETHUSD"]));
+        assert_eq!(
+            set.replay_messages(),
+            vec![WsMessage::Binary(
+                br#"{"op":"subscribe","args":["BTCUSD","ETHUSD"]}"#.to_vec()
+            )]
+        );
+        assert!(matches!(
+            set.unsubscribe_messages(&topics(&["BTCUSD"])),
+            Err(SubscriptionError::MissingTemplate(ref name))
+                if name == "WEBSOCKET_UNSUBSCRIBE_TEMPLATE"
+        ));
+        assert!(SubscriptionSet::default().replay_messages().is_empty());
+    }
+
+    #[test]
+    fn test_subscription_set_adopts_initial_subscriptions() {
+        let mut set = SubscriptionSet::from_config(&subscription_config());
+        let setup = set.adopt(vec![
+            WsMessage::Text("auth".to_string()),
+            WsMessage::Text("sub:XRPUSD".to_string()),
+            WsMessage::Text("sub:BTCUSD".to_string()),
+            WsMessage::Binary(b"sub:SOLUSD".to_vec()),
+            WsMessage::Text("sub:".to_string()),
+        ]);
+        assert_eq!(
+            setup,
+            vec![
+                WsMessage::Text("auth".to_string()),
+                WsMessage::Binary(b"sub:SOLUSD".to_vec()),
+                WsMessage::Text("sub:".to_string()),
+            ]
+        );
+        assert_eq!(set.topics(), ["BTCUSD", "ETHUSD", "XRPUSD"]);
+
+        let config = Config {
+            websocket_subscribe_template: Some(
+                r#"{"op":"subscribe","args":{symbols}}"#.to_string(),
+            ),
+            ..Default::default()
+        };
+        let mut set = SubscriptionSet::from_config(&config);
+        let setup = set.adopt(vec![
+            WsMessage::Text(r#"{"op":"subscribe","args":["BTCUSD","ETHUSD"]}"#.to_string()),
+            WsMessage::Text(r#"{"op":"subscribe","args":"BTCUSD"}"#.to_string()),
+        ]);
+        assert_eq!(setup.len(), 1);
+        assert_eq!(set.topics(), ["BTCUSD", "ETHUSD"]);
+
+        // Escaped symbols are adopted as they were before escaping
+        let mut set = SubscriptionSet::from_config(&Config {
+            websocket_subscribe_template: Some(r#"{"sub":"{symbol}"}"#.to_string()),
+            ..Default::default()
+        });
+        let quoted = WsMessage::Text(r#"{"sub":"say \"hi\""}"#.to_string());
+        assert!(set.adopt(vec![quoted]).is_empty());
+        assert_eq!(set.topics(), [r#"say "hi""#]);
+
+        // Without a template there is nothing to manage
+        let messages = vec![WsMessage::Text("sub:BTCUSD".to_string())];
+        assert_eq!(SubscriptionSet::default().adopt(messages.clone()), messages);
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_change_at_runtime_and_are_replayed() {
+        let (url, mut received) = spawn_recording_server(vec![]).await;
+        let config = Config {
+            websocket_url: url,
+            websocket_reconnect_initial_delay_ms: 1,
+            ..subscription_config()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let initial = vec![
+            WsMessage::Text("sub:XRPUSD".to_string()),
+            WsMessage::Text("hello".to_string()),
+        ];
+        let mut ws_client = WebSocketClient::new(config, None, initial, mongo_client);
+        let subscriptions = ws_client.subscriptions();
+        let outbound = ws_client.outbound_sender();
+        let stats = ws_client.stats();
+        let run = tokio::spawn(async move { ws_client.run().await });
+
+        assert_eq!(next_text(&mut received).await, "hello");
+        assert_eq!(next_text(&mut received).await, "sub:BTCUSD");
+        assert_eq!(next_text(&mut received).await, "sub:ETHUSD");
+        assert_eq!(next_text(&mut received).await, "sub:XRPUSD");
+        while !stats.connected.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let added = subscriptions
+            .subscribe(&topics(&["SOLUSD", "BTCUSD"]))
+            .await
+            .unwrap();
+        assert_eq!(added, ["SOLUSD"]);
+        assert_eq!(next_text(&mut received).await, "sub:SOLUSD");
+        subscriptions
+            .unsubscribe(&topics(&["BTCUSD", "XRPUSD"]))
+            .await
+            .unwrap();
+        assert_eq!(next_text(&mut received).await, "unsub:BTCUSD");
+        assert_eq!(next_text(&mut received).await, "unsub:XRPUSD");
+        assert_eq!(subscriptions.topics().await, ["ETHUSD", "SOLUSD"]);
+
+        // After a reconnect the current set is replayed, not the configured symbols, and the
+        // topics unsubscribed at runtime stay unsubscribed, even the one from initial messages
+        outbound
+            .send(WsMessage::Text("bye".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(next_text(&mut received).await, "hello");
+        assert_eq!(next_text(&mut received).await, "sub:ETHUSD");
+        assert_eq!(next_text(&mut received).await, "sub:SOLUSD");
+        outbound
+            .send(WsMessage::Text("ping".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(next_text(&mut received).await, "ping");
+        run.abort();
+    }
+
+    #[tokio::test]
+    async fn test_subscription_changes_do_not_hold_the_set_while_the_channel_is_full() {
+        let (url, _received) = spawn_recording_server(vec![]).await;
+        let config = Config {
+            websocket_url: url,
+            ..subscription_config()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut ws_client = WebSocketClient::new(config, None, Vec::new(), mongo_client);
+        ws_client.connect().await.unwrap();
+
+        // No writer drains the channel, as while a dropped connection is being re-established
+        let outbound = ws_client.outbound_sender();
+        while outbound
+            .try_send(WsMessage::Text("filler".to_string()))
+            .is_ok()
+        {}
+        let subscriptions = ws_client.subscriptions();
+        let blocked = subscriptions.clone();
+        let subscribe = tokio::spawn(async move { blocked.subscribe(&topics(&["SOLUSD"])).await });
+
+        // The change waits for room in the channel, but the set is already updated and free
+        let topics = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let topics = subscriptions.topics().await;
+                if topics.len() == 3 {
+                    return topics;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the subscription set should not stay locked");
+        assert_eq!(topics, ["BTCUSD", "ETHUSD", "SOLUSD"]);
+        assert!(!subscribe.is_finished());
+        subscribe.abort();
+    }
+}
@@ -21,78 +21,157 @@
    Date: 11/5/24
 ******************************************************************************/
 
+mod common;
+
+use common::{lazy_mongo_client, next_received, spawn_recording_server};
 use futures_util::{SinkExt, StreamExt};
-use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use ws2mongo::config::Config;
+
+/// Starts a WebSocket server on a random local port that accepts a single connection.
+///
+/// The server sends `to_send` (if any) and reports the first message it receives.
+async fn spawn_server(to_send: Option<WsMessage>) -> (String, oneshot::Receiver<WsMessage>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = accept_async(stream).await.unwrap();
+        if let Some(message) = to_send {
+            socket.send(message).await.unwrap();
+        }
+        if let Some(Ok(message)) = socket.next().await {
+            let _ = tx.send(message);
+        }
+    });
+
+    (format!("ws://{}", address), rx)
+}
+
+/// Starts a WebSocket server that answers the first message of every connection with `response`.
+///
+/// Every message received is forwarded on the returned channel, and the number of accepted
+/// connections is reported through the returned counter.
+async fn spawn_auth_server(
+    response: &'static str,
+) -> (
+    String,
+    tokio::sync::mpsc::UnboundedReceiver<WsMessage>,
+    Arc<std::sync::atomic::AtomicUsize>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = Arc::clone(&connections);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut socket = accept_async(stream).await.unwrap();
+                let mut answered = false;
+                while let Some(Ok(message)) = socket.next().await {
+                    let _ = tx.send(message);
+                    if !answered {
+                        answered = true;
+                        let _ = socket.send(WsMessage::Text(response.to_string())).await;
+                    }
+                }
+            });
+        }
+    });
+
+    (format!("ws://{}", address), rx, connections)
+}
+
+/// Starts a WebSocket server that accepts connections but never reads from them, counting the
+/// accepted connections.
+async fn spawn_silent_server() -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = Arc::clone(&connections);
+
+    tokio::spawn(async move {
+        let mut sockets = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            sockets.push(accept_async(stream).await.unwrap());
+        }
+    });
+
+    (format!("ws://{}", address), connections)
+}
 
 #[cfg(test)]
 mod websocket_tests {
     use super::*;
-    use mockall::predicate::*;
-    use tokio_tungstenite::tungstenite::{error::Error as WsError, Message as WsMessage};
-    use ws2mongo::config::Config;
-    use ws2mongo::websocket::WebSocketClient;
+    use std::time::Duration;
+    use ws2mongo::config::{
+        AuthMessageConfig, ConfigError, ErrorPolicy, HmacAlgorithm, InitialMessage,
+        SignatureEncoding, WebSocketAuthMode,
+    };
+    use ws2mongo::mongodb::MongoClientError;
+    use ws2mongo::utils::DecodeError;
+    use ws2mongo::websocket::{
+        handshake_headers, initial_messages, Backoff, Heartbeat, IdleWatchdog, ReconnectPolicy,
+        WebSocketClient, WebSocketError,
+    };
 
-    trait MockWebSocketStream {
-        fn new() -> Self;
-        async fn send(&mut self, msg: WsMessage) -> Result<(), WsError>;
-        async fn receive(&mut self) -> Option<Result<WsMessage, WsError>>;
+    fn policy(max_attempts: Option<u32>) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            multiplier: 2.0,
+            max_attempts,
+            reset_after: Duration::from_secs(60),
+        }
     }
 
     #[tokio::test]
     async fn test_send_message_success() {
-        let mut mock_stream = MockWebSocketStream::new();
+        let (url, received) = spawn_server(None).await;
         let config = Config {
-            websocket_url: String::from("ws://example.com"),
-            websocket_api_key: None,
-            websocket_api_secret: None,
-            mongodb_uri: "".to_string(),
-            database_name: "".to_string(),
-            collection_name: "".to_string(),
-            mongodb_user: None,
-            mongodb_password: None,
-            mongodb_auth_source: None,
-            mongodb_auth_mechanism: None,
+            websocket_url: url,
+            ..Default::default()
         };
-
-        mock_stream
-            .expect_send()
-            .times(1)
-            .with(eq(WsMessage::Text("Hello WebSocket".to_string())))
-            .returning(|_| Ok(()));
+        let mongo_client = lazy_mongo_client(&config).await;
 
         let messages_to_send = vec![];
-
-        let mut client = WebSocketClient::new(config, Some(mock_stream), messages_to_send);
+        let mut client = WebSocketClient::new(config, None, messages_to_send, mongo_client);
+        client.connect().await.unwrap();
 
         let result = client
             .send_message(WsMessage::Text("Hello WebSocket".to_string()))
             .await;
         assert!(result.is_ok());
+        assert_eq!(
+            received.await.unwrap(),
+            WsMessage::Text("Hello WebSocket".to_string())
+        );
     }
 
     #[tokio::test]
     async fn test_receive_message_success() {
-        let mut mock_stream = MockWebSocketStream::new();
+        let expected_msg = WsMessage::Text("Hello from WebSocket".to_string());
+        let (url, _received) = spawn_server(Some(expected_msg)).await;
         let config = Config {
-            websocket_url: String::from("ws://example.com"),
-            websocket_api_key: None,
-            websocket_api_secret: None,
-            mongodb_uri: "".to_string(),
-            database_name: "".to_string(),
-            collection_name: "".to_string(),
-            mongodb_user: None,
-            mongodb_password: None,
-            mongodb_auth_source: None,
-            mongodb_auth_mechanism: None,
+            websocket_url: url,
+            ..Default::default()
         };
+        let mongo_client = lazy_mongo_client(&config).await;
 
-        let expected_msg = WsMessage::Text("Hello from WebSocket".to_string());
-        mock_stream
-            .expect_receive()
-            .times(1)
-            .returning(move || Some(Ok(expected_msg.clone())));
         let messages_to_send = vec![];
-        let mut client = WebSocketClient::new(config, Some(mock_stream), messages_to_send);
+        let mut client = WebSocketClient::new(config, None, messages_to_send, mongo_client);
+        client.connect().await.unwrap();
 
         let result = client.receive_message().await;
         assert!(result.is_ok());
@@ -101,4 +180,481 @@ mod websocket_tests {
             WsMessage::Text("Hello from WebSocket".to_string())
         );
     }
+
+    #[tokio::test]
+    async fn test_send_message_without_connection() {
+        let config = Config::default();
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+
+        let result = client
+            .send_message(WsMessage::Text("Hello WebSocket".to_string()))
+            .await;
+        assert!(matches!(result, Err(WebSocketError::NotConnected)));
+    }
+
+    #[test]
+    fn test_reconnect_policy_ceiling_grows_and_caps() {
+        let policy = policy(None);
+        assert_eq!(policy.ceiling(1), Duration::from_millis(100));
+        assert_eq!(policy.ceiling(2), Duration::from_millis(200));
+        assert_eq!(policy.ceiling(4), Duration::from_millis(800));
+        assert_eq!(policy.ceiling(5), Duration::from_millis(1000));
+        assert_eq!(policy.ceiling(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_backoff_full_jitter_stays_below_ceiling() {
+        let policy = policy(None);
+        let mut backoff = Backoff::new(policy.clone());
+        for attempt in 1..=20 {
+            let delay = backoff.next_delay().unwrap();
+            assert!(delay <= policy.ceiling(attempt));
+        }
+        assert_eq!(backoff.attempts(), 20);
+    }
+
+    #[test]
+    fn test_backoff_budget_and_reset() {
+        let mut backoff = Backoff::new(policy(Some(3)));
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+
+        // A short-lived connection keeps the counter, a stable one clears it
+        assert!(!backoff.connection_ended(Duration::from_secs(1)));
+        assert_eq!(backoff.attempts(), 2);
+        assert!(backoff.connection_ended(Duration::from_secs(60)));
+        assert_eq!(backoff.attempts(), 0);
+
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_none());
+        assert_eq!(backoff.attempts(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_run_gives_up_when_connection_is_refused() {
+        // Bind and drop a listener to get a local port nobody listens on
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+
+        let config = Config {
+            websocket_url: format!("ws://{}", address),
+            websocket_reconnect_initial_delay_ms: 1,
+            websocket_reconnect_max_delay_ms: 5,
+            websocket_reconnect_max_attempts: Some(3),
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+
+        let result = tokio::time::timeout(Duration::from_secs(10), client.run())
+            .await
+            .expect("run should give up instead of retrying forever");
+        assert!(matches!(
+            result,
+            Err(WebSocketError::ReconnectAttemptsExhausted(3, _))
+        ));
+    }
+
+    #[test]
+    fn test_handshake_headers() {
+        let mut config = Config {
+            websocket_api_key: Some("user".to_string()),
+            websocket_api_secret: Some("pass".into()),
+            ..Default::default()
+        };
+        config
+            .websocket_headers
+            .insert("X-Api-Key".to_string(), "user".into());
+        assert_eq!(
+            handshake_headers(&config),
+            vec![("X-Api-Key".to_string(), "user".to_string())]
+        );
+
+        config.websocket_auth_mode = WebSocketAuthMode::Bearer;
+        assert_eq!(
+            handshake_headers(&config)[0],
+            ("Authorization".to_string(), "Bearer user".to_string())
+        );
+
+        config.websocket_auth_mode = WebSocketAuthMode::Basic;
+        assert_eq!(
+            handshake_headers(&config)[0],
+            (
+                "Authorization".to_string(),
+                "Basic dXNlcjpwYXNz".to_string()
+            )
+        );
+    }
+
+    fn auth_config() -> AuthMessageConfig {
+        AuthMessageConfig {
+            template: r#"{"action":"auth","key":"{api_key}","signature":"{signature}"}"#
+                .to_string(),
+            signature_payload: "{timestamp}{nonce}".to_string(),
+            signature_algorithm: HmacAlgorithm::Sha256,
+            signature_encoding: SignatureEncoding::Hex,
+            success_pointer: Some("/status".to_string()),
+            success_value: "ok".to_string(),
+            failure_pointer: Some("/error".to_string()),
+            failure_value: String::new(),
+            timeout_ms: 2000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_message_is_sent_before_initial_messages() {
+        let (url, mut received, _) = spawn_auth_server(r#"{"status":"ok"}"#).await;
+        let config = Config {
+            websocket_url: url,
+            websocket_api_key: Some("my-key".to_string()),
+            websocket_api_secret: Some("my-secret".into()),
+            websocket_auth_message: Some(auth_config()),
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let subscribe = WsMessage::Text(r#"{"action":"subscribe"}"#.to_string());
+        let mut client = WebSocketClient::new(config, None, vec![subscribe.clone()], mongo_client);
+
+        client.connect().await.unwrap();
+
+        let auth = received.recv().await.unwrap().into_text().unwrap();
+        let auth: serde_json::Value = serde_json::from_str(&auth).unwrap();
+        assert_eq!(auth["key"], "my-key");
+        assert_eq!(auth["signature"].as_str().unwrap().len(), 64);
+        assert_eq!(received.recv().await.unwrap(), subscribe);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_auth_stops_run_without_reconnecting() {
+        let (url, _received, connections) =
+            spawn_auth_server(r#"{"error":"invalid signature"}"#).await;
+        let config = Config {
+            websocket_url: url,
+            websocket_api_key: Some("my-key".to_string()),
+            websocket_api_secret: Some("my-secret".into()),
+            websocket_auth_message: Some(auth_config()),
+            websocket_reconnect_initial_delay_ms: 1,
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+
+        let result = tokio::time::timeout(Duration::from_secs(10), client.run())
+            .await
+            .expect("run should stop on an authentication failure");
+        assert!(matches!(result, Err(WebSocketError::Authentication(_))));
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_auth_is_retried() {
+        // The server answers, but never with the success or failure value
+        let (url, _received, connections) = spawn_auth_server(r#"{"status":"pending"}"#).await;
+        let config = Config {
+            websocket_url: url,
+            websocket_api_key: Some("my-key".to_string()),
+            websocket_api_secret: Some("my-secret".into()),
+            websocket_auth_message: Some(AuthMessageConfig {
+                timeout_ms: 50,
+                ..auth_config()
+            }),
+            websocket_reconnect_initial_delay_ms: 1,
+            websocket_reconnect_max_attempts: Some(2),
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+
+        let result = tokio::time::timeout(Duration::from_secs(10), client.run())
+            .await
+            .expect("run should give up after the reconnection attempts");
+        assert!(
+            matches!(result, Err(WebSocketError::ReconnectAttemptsExhausted(2, ref reason))
+                if reason.contains("no authentication response within 50 ms")),
+            "{:?}",
+            result
+        );
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_heartbeat_from_config() {
+        let mut config = Config::default();
+        assert!(Heartbeat::from_config(&config).is_none());
+
+        config.websocket_heartbeat_interval_ms = Some(1000);
+        assert_eq!(
+            Heartbeat::from_config(&config).unwrap().message,
+            WsMessage::Ping(vec![])
+        );
+
+        config.websocket_heartbeat_message = Some(r#"{"op":"ping"}"#.to_string());
+        let heartbeat = Heartbeat::from_config(&config).unwrap();
+        assert_eq!(heartbeat.interval, Duration::from_secs(1));
+        assert_eq!(
+            heartbeat.message,
+            WsMessage::Text(r#"{"op":"ping"}"#.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_answers_pings_and_sends_heartbeats() {
+        let (url, mut received) =
+            spawn_recording_server(vec![WsMessage::Ping(b"are you there".to_vec())]).await;
+        let config = Config {
+            websocket_url: url,
+            websocket_heartbeat_interval_ms: Some(50),
+            websocket_heartbeat_message: Some(r#"{"op":"ping"}"#.to_string()),
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+        let run = tokio::spawn(async move { client.run().await });
+
+        assert_eq!(
+            next_received(&mut received, |_| false).await,
+            WsMessage::Pong(b"are you there".to_vec())
+        );
+        // The ping is answered once
+        assert_eq!(
+            next_received(&mut received, |_| false).await,
+            WsMessage::Text(r#"{"op":"ping"}"#.to_string())
+        );
+        run.abort();
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_reconnects() {
+        let (url, connections) = spawn_silent_server().await;
+        let config = Config {
+            websocket_url: url,
+            websocket_reconnect_initial_delay_ms: 1,
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+        let outbound = client.outbound_sender();
+        let run = tokio::spawn(async move { client.run().await });
+
+        // Nothing can be written after a close frame, while the read half stays open since the
+        // server never answers the close
+        outbound.send(WsMessage::Close(None)).await.unwrap();
+        outbound
+            .send(WsMessage::Text("too late".to_string()))
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while connections.load(std::sync::atomic::Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the client should reconnect once its writer stopped");
+        run.abort();
+    }
+
+    #[tokio::test]
+    async fn test_run_writes_outbound_messages() {
+        let (url, mut received) = spawn_recording_server(vec![]).await;
+        let config = Config {
+            websocket_url: url,
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+        let outbound = client.outbound_sender();
+        let run = tokio::spawn(async move { client.run().await });
+
+        let subscribe = WsMessage::Text(r#"{"op":"subscribe","args":["BTCUSD"]}"#.to_string());
+        outbound.send(subscribe.clone()).await.unwrap();
+        assert_eq!(next_received(&mut received, |_| false).await, subscribe);
+        run.abort();
+    }
+
+    #[test]
+    fn test_idle_watchdog_activity() {
+        let text = |value: &str| WsMessage::Text(value.to_string());
+        let mut watchdog = IdleWatchdog {
+            timeout: Duration::from_secs(1),
+            data_only: false,
+            data_pointer: None,
+        };
+        assert!(watchdog.is_activity(&WsMessage::Ping(vec![])));
+
+        watchdog.data_only = true;
+        assert!(!watchdog.is_activity(&WsMessage::Ping(vec![])));
+        assert!(watchdog.is_activity(&text(r#"{"event":"heartbeat"}"#)));
+
+        watchdog.data_pointer = Some("/data".to_string());
+        assert!(!watchdog.is_activity(&text(r#"{"event":"heartbeat"}"#)));
+        assert!(watchdog.is_activity(&text(r#"{"data":{"p":"1.0"}}"#)));
+        assert!(!watchdog.is_activity(&text("not json")));
+    }
+
+    #[tokio::test]
+    async fn test_run_reconnects_stale_connection() {
+        let (url, _received) = spawn_recording_server(vec![]).await;
+        let config = Config {
+            websocket_url: url,
+            websocket_idle_timeout_ms: Some(100),
+            websocket_reconnect_initial_delay_ms: 1,
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+        let stats = client.stats();
+        let run = tokio::spawn(async move { client.run().await });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while stats
+                .idle_timeouts
+                .load(std::sync::atomic::Ordering::Relaxed)
+                < 2
+            {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the silent connection should be dropped repeatedly");
+        assert!(!run.is_finished());
+        run.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connect_errors_are_classified() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused = listener.local_addr().unwrap();
+        drop(listener);
+
+        // A plain HTTP server that rejects the upgrade
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await;
+        });
+
+        let config = Config::default();
+        let mongo_client = lazy_mongo_client(&config).await;
+
+        let mut client = WebSocketClient::new(
+            Config {
+                websocket_url: format!("ws://{}", refused),
+                ..Default::default()
+            },
+            None,
+            vec![],
+            Arc::clone(&mongo_client),
+        );
+        assert!(matches!(
+            client.connect().await,
+            Err(WebSocketError::Connect(_))
+        ));
+
+        let mut client = WebSocketClient::new(
+            Config {
+                websocket_url: format!("ws://{}", http),
+                ..Default::default()
+            },
+            None,
+            vec![],
+            mongo_client,
+        );
+        let error = client.connect().await.unwrap_err();
+        assert!(matches!(error, WebSocketError::Handshake(_)));
+        assert!(matches!(
+            ws2mongo::error::Error::from(error),
+            ws2mongo::error::Error::WebSocket(WebSocketError::Handshake(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_url_is_a_config_error() {
+        for url in ["not a url", "unix:/var/run/feed.sock"] {
+            let config = Config {
+                websocket_url: url.to_string(),
+                ..Default::default()
+            };
+            let mongo_client = lazy_mongo_client(&config).await;
+            let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+
+            let result = tokio::time::timeout(Duration::from_secs(5), client.run())
+                .await
+                .expect("run should stop on an invalid URL");
+            assert!(matches!(
+                result,
+                Err(WebSocketError::Config(ConfigError::InvalidUrl(_, _)))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frames_follow_the_decode_policy() {
+        let greeting = vec![WsMessage::Text("not json".to_string())];
+
+        let (url, _received) = spawn_recording_server(greeting.clone()).await;
+        let config = Config {
+            websocket_url: url,
+            decode_error_policy: ErrorPolicy::Log,
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+        let run = tokio::spawn(async move { client.run().await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!run.is_finished());
+        run.abort();
+
+        let (url, _received) = spawn_recording_server(greeting).await;
+        let config = Config {
+            websocket_url: url,
+            decode_error_policy: ErrorPolicy::Abort,
+            ..Default::default()
+        };
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, vec![], mongo_client);
+        let result = tokio::time::timeout(Duration::from_secs(5), client.run())
+            .await
+            .expect("run should stop on the malformed frame");
+        assert!(matches!(
+            result,
+            Err(WebSocketError::Mongo(MongoClientError::Decode(
+                DecodeError::Json(_)
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_initial_messages_are_spaced_by_the_delay() {
+        let (url, mut received) = spawn_recording_server(vec![]).await;
+        let config = Config {
+            websocket_url: url,
+            websocket_initial_messages: vec![
+                InitialMessage::Text("first".to_string()),
+                InitialMessage::Binary(vec![2]),
+            ],
+            websocket_initial_messages_delay_ms: Some(150),
+            ..Default::default()
+        };
+        let messages = initial_messages(&config);
+        let mongo_client = lazy_mongo_client(&config).await;
+        let mut client = WebSocketClient::new(config, None, messages, mongo_client);
+
+        let started = tokio::time::Instant::now();
+        client.connect().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(
+            received.recv().await.unwrap(),
+            WsMessage::Text("first".to_string())
+        );
+        assert_eq!(received.recv().await.unwrap(), WsMessage::Binary(vec![2]));
+    }
 }